@@ -0,0 +1,397 @@
+// Sign-in, sessions and local accounts
+
+use super::*;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct UserAccount {
+    pub(crate) username: String,
+    pub(crate) password_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct UserStore {
+    pub(crate) users: Vec<UserAccount>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AuthSource {
+    Local,
+    Ldap,
+}
+
+// The signed-in user, inserted into request extensions by `require_login`
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct CurrentUser {
+    pub(crate) username: String,
+    pub(crate) source: AuthSource,
+}
+
+pub(crate) struct Session {
+    pub(crate) user: CurrentUser,
+    pub(crate) expires: Instant,
+}
+
+pub(crate) const SESSION_COOKIE: &str = "db_session";
+
+pub(crate) static USER_STORE: Lazy<RwLock<UserStore>> = Lazy::new(|| RwLock::new(load_store("users.json")));
+pub(crate) static SESSIONS: Lazy<DashMap<String, Session>> = Lazy::new(DashMap::new);
+
+// Recent failed sign-ins, keyed "user:<name>" and "ip:<address>"
+pub(crate) struct LoginFailures {
+    pub(crate) count: u32,
+    pub(crate) last: Instant,
+}
+
+pub(crate) static LOGIN_FAILURES: Lazy<DashMap<String, LoginFailures>> = Lazy::new(DashMap::new);
+
+// Clients share an address behind a proxy or NAT, so an address gets more tries than a username
+pub(crate) const LOGIN_FAILURES_PER_ADDRESS: u32 = 4;
+
+#[derive(Deserialize)]
+pub(crate) struct LoginRequest {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChangePasswordRequest {
+    pub(crate) current_password: String,
+    pub(crate) new_password: String,
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, String> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+    let salt = SaltString::generate(&mut OsRng);
+    argon2::Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub(crate) fn verify_password(password: &str, hash: &str) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+    match PasswordHash::new(hash) {
+        Ok(parsed) => argon2::Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub(crate) fn new_session_token() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn session_token(headers: &axum::http::HeaderMap) -> Option<String> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+pub(crate) fn session_user(headers: &axum::http::HeaderMap) -> Option<CurrentUser> {
+    let token = session_token(headers)?;
+    let expired = {
+        let session = SESSIONS.get(&token)?;
+        if session.expires > Instant::now() {
+            return Some(session.user.clone());
+        }
+        true
+    };
+    if expired {
+        SESSIONS.remove(&token);
+    }
+    None
+}
+
+// Drop expired sessions and sign-in failures past their lockout, run every minute
+// so they do not pile up for users who never come back
+pub(crate) fn sweep_sessions() {
+    let now = Instant::now();
+    SESSIONS.retain(|_, s| s.expires > now);
+    let lockout = Duration::from_secs(CONFIG.login_lockout_minutes * 60);
+    LOGIN_FAILURES.retain(|_, f| f.last.elapsed() < lockout);
+}
+
+pub(crate) fn login_failure_keys(username: &str, addr: &SocketAddr) -> [(String, u32); 2] {
+    [
+        (format!("user:{}", username.to_lowercase()), CONFIG.login_max_failures),
+        (format!("ip:{}", addr.ip()), CONFIG.login_max_failures * LOGIN_FAILURES_PER_ADDRESS),
+    ]
+}
+
+// Refuse a sign-in while its username or address has failed too often, until the
+// lockout has passed since the last failure
+pub(crate) fn check_login_allowed(username: &str, addr: &SocketAddr) -> Result<(), AppError> {
+    let lockout = Duration::from_secs(CONFIG.login_lockout_minutes * 60);
+    for (key, limit) in login_failure_keys(username, addr) {
+        if let Some(failures) = LOGIN_FAILURES.get(&key) {
+            let elapsed = failures.last.elapsed();
+            if failures.count >= limit && elapsed < lockout {
+                let retry = (lockout - elapsed).as_secs() / 60 + 1;
+                return Err(AppError::new(ErrorKind::TooManyRequests, format!("Too many failed sign-ins; try again in {} minutes", retry))
+                    .with_details(serde_json::json!({ "retry_after_seconds": (lockout - elapsed).as_secs() })));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn record_login_failure(username: &str, addr: &SocketAddr) {
+    let lockout = Duration::from_secs(CONFIG.login_lockout_minutes * 60);
+    for (key, _) in login_failure_keys(username, addr) {
+        let mut failures = LOGIN_FAILURES.entry(key).or_insert(LoginFailures { count: 0, last: Instant::now() });
+        // Failures older than the lockout no longer count
+        if failures.last.elapsed() >= lockout {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = Instant::now();
+    }
+    tracing::warn!(username = %username, client = %addr.ip(), "failed sign-in");
+}
+
+pub(crate) fn session_cookie(token: &str, max_age: u64) -> String {
+    let secure = if CONFIG.cookie_secure { "; Secure" } else { "" };
+    format!("{SESSION_COOKIE}={token}; Path=/; HttpOnly; SameSite=Lax; Max-Age={max_age}{secure}")
+}
+
+// Create the first local account so a fresh install can be signed into
+pub(crate) async fn bootstrap_admin_account() {
+    {
+        let mut grants = GRANT_STORE.write().await;
+        if grants.grants.is_empty() {
+            grants.grants.push(Grant { username: "admin".to_string(), database: "*".to_string(), role: Role::Admin });
+            if let Err(e) = save_store("grants.json", &*grants) {
+                tracing::error!("Failed to save grants.json: {e}");
+            }
+        }
+    }
+    let mut store = USER_STORE.write().await;
+    if !store.users.is_empty() {
+        return;
+    }
+    let (password, generated) = match std::env::var("DATABASE_ADMIN_PASSWORD") {
+        Ok(p) if !p.is_empty() => (p, false),
+        _ => (new_session_token()[..16].to_string(), true),
+    };
+    let password_hash = hash_password(&password).expect("Failed to hash admin password");
+    store.users.push(UserAccount { username: "admin".to_string(), password_hash });
+    if let Err(e) = save_store("users.json", &*store) {
+        tracing::error!("Failed to save users.json: {e}");
+    }
+    if generated {
+        // Straight to the console: the password must not end up in the log files
+        println!("Created local account 'admin' with password: {password}");
+    } else {
+        tracing::info!("Created local account 'admin' from DATABASE_ADMIN_PASSWORD");
+    }
+}
+
+// Whether the password matches, or None when there is no local account of that name
+pub(crate) async fn authenticate_local(username: &str, password: &str) -> Option<bool> {
+    let hash = {
+        let store = USER_STORE.read().await;
+        store.users.iter().find(|u| u.username.eq_ignore_ascii_case(username))?.password_hash.clone()
+    };
+    let password = password.to_string();
+    let ok = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    Some(ok)
+}
+
+pub(crate) async fn authenticate_ldap(url: &str, username: &str, password: &str) -> Result<bool, String> {
+    // An empty password would be an anonymous bind, which most directories accept
+    if password.is_empty() {
+        return Ok(false);
+    }
+    let bind_dn = CONFIG.ldap_bind_dn.replace("{username}", &ldap3::dn_escape(username));
+    let (conn, mut ldap) = ldap3::LdapConnAsync::new(url).await.map_err(|e| e.to_string())?;
+    ldap3::drive!(conn);
+    let result = ldap.simple_bind(&bind_dn, password).await.map_err(|e| e.to_string())?;
+    let ok = result.success().is_ok();
+    let _ = ldap.unbind().await;
+    Ok(ok)
+}
+
+pub(crate) async fn login_page() -> impl IntoResponse {
+    Html(include_str!("../static/login.html"))
+}
+
+pub(crate) async fn login(axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<SocketAddr>, Json(payload): Json<LoginRequest>) -> axum::response::Response {
+    let LoginRequest { username, password } = payload;
+    let username = username.trim().to_string();
+    if username.is_empty() {
+        return AppError::new(ErrorKind::Unauthorized, "Invalid username or password").into_response();
+    }
+    if let Err(e) = check_login_allowed(&username, &addr) {
+        return e.into_response();
+    }
+    // Grants and sessions go by username, so a name with a local account never
+    // falls back to the directory, where someone else may hold it
+    let source = match (authenticate_local(&username, &password).await, &CONFIG.ldap_url) {
+        (Some(ok), _) => ok.then_some(AuthSource::Local),
+        (None, Some(url)) => match authenticate_ldap(url, &username, &password).await {
+            Ok(true) => Some(AuthSource::Ldap),
+            Ok(false) => None,
+            Err(e) => {
+                tracing::error!(username = %username, "directory sign-in failed: {e}");
+                return AppError::new(ErrorKind::Unavailable, "The directory server could not be reached").into_response();
+            }
+        },
+        (None, None) => None,
+    };
+    let Some(source) = source else {
+        record_login_failure(&username, &addr);
+        return AppError::new(ErrorKind::Unauthorized, "Invalid username or password").into_response();
+    };
+    LOGIN_FAILURES.remove(&login_failure_keys(&username, &addr)[0].0);
+    let user = CurrentUser { username, source };
+    let token = new_session_token();
+    let max_age = CONFIG.session_hours * 3600;
+    SESSIONS.insert(token.clone(), Session {
+        user: user.clone(),
+        expires: Instant::now() + Duration::from_secs(max_age),
+    });
+    let mut response = Json(user).into_response();
+    if let Ok(cookie) = session_cookie(&token, max_age).parse() {
+        response.headers_mut().insert(axum::http::header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+pub(crate) async fn logout(headers: axum::http::HeaderMap) -> axum::response::Response {
+    if let Some(token) = session_token(&headers) {
+        SESSIONS.remove(&token);
+    }
+    let mut response = axum::response::Response::new("OK".into());
+    if let Ok(cookie) = session_cookie("", 0).parse() {
+        response.headers_mut().insert(axum::http::header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+pub(crate) async fn get_current_user(Extension(user): Extension<CurrentUser>) -> axum::response::Response {
+    let grants: Vec<Grant> = {
+        let store = GRANT_STORE.read().await;
+        store.grants.iter().filter(|g| g.username.eq_ignore_ascii_case(&user.username)).cloned().collect()
+    };
+    Json(serde_json::json!({ "username": user.username, "source": user.source, "grants": grants })).into_response()
+}
+
+pub(crate) async fn change_password(Extension(user): Extension<CurrentUser>, Json(payload): Json<ChangePasswordRequest>) -> axum::response::Response {
+    if user.source != AuthSource::Local {
+        return AppError::validation("Directory accounts change their password in the directory").into_response();
+    }
+    if payload.new_password.len() < 8 {
+        return AppError::validation("New password must be at least 8 characters").into_response();
+    }
+    if authenticate_local(&user.username, &payload.current_password).await != Some(true) {
+        return AppError::new(ErrorKind::Unauthorized, "Current password is incorrect").into_response();
+    }
+    let new_password = payload.new_password;
+    let password_hash = match tokio::task::spawn_blocking(move || hash_password(&new_password)).await {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => return AppError::internal(e).into_response(),
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let mut store = USER_STORE.write().await;
+    if let Some(account) = store.users.iter_mut().find(|u| u.username.eq_ignore_ascii_case(&user.username)) {
+        account.password_hash = password_hash;
+    }
+    match save_store("users.json", &*store) {
+        Ok(()) => axum::response::Response::new("OK".into()),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+// Middleware: resolve the session cookie to a CurrentUser or turn the request away
+pub(crate) async fn require_login(mut req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    match session_user(req.headers()) {
+        Some(user) => {
+            tracing::Span::current().record("user", user.username.as_str());
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        None if req.uri().path().starts_with("/api/") => {
+            AppError::new(ErrorKind::Unauthorized, "Not signed in").into_response()
+        }
+        None => axum::response::Redirect::to("/login").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AddUserRequest {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteUserRequest {
+    pub(crate) username: String,
+}
+
+pub(crate) async fn admin_list_users() -> axum::response::Response {
+    let store = USER_STORE.read().await;
+    let users: Vec<&str> = store.users.iter().map(|u| u.username.as_str()).collect();
+    Json(serde_json::json!({ "users": users })).into_response()
+}
+
+pub(crate) async fn admin_add_user(Json(payload): Json<AddUserRequest>) -> axum::response::Response {
+    let AddUserRequest { username, password } = payload;
+    let username = username.trim().to_string();
+    if username.is_empty() || password.len() < 8 {
+        return AppError::validation("Username is required and password must be at least 8 characters").into_response();
+    }
+    let password_hash = match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => return AppError::internal(e).into_response(),
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let mut store = USER_STORE.write().await;
+    if store.users.iter().any(|u| u.username.eq_ignore_ascii_case(&username)) {
+        return AppError::conflict(format!("User {username} already exists")).into_response();
+    }
+    store.users.push(UserAccount { username: username.clone(), password_hash });
+    if let Err(e) = save_store("users.json", &*store) {
+        return AppError::internal(e).into_response();
+    }
+    // The name now belongs to the local account, so directory sessions under it end
+    SESSIONS.retain(|_, s| !(s.user.source == AuthSource::Ldap && s.user.username.eq_ignore_ascii_case(&username)));
+    axum::response::Response::new("OK".into())
+}
+
+pub(crate) async fn admin_delete_user(Json(payload): Json<DeleteUserRequest>) -> axum::response::Response {
+    let DeleteUserRequest { username } = payload;
+    {
+        let mut store = USER_STORE.write().await;
+        store.users.retain(|u| !u.username.eq_ignore_ascii_case(&username));
+        if let Err(e) = save_store("users.json", &*store) {
+            return AppError::internal(e).into_response();
+        }
+    }
+    {
+        let mut grants = GRANT_STORE.write().await;
+        grants.grants.retain(|g| !g.username.eq_ignore_ascii_case(&username));
+        if let Err(e) = save_store("grants.json", &*grants) {
+            return AppError::internal(e).into_response();
+        }
+    }
+    SESSIONS.retain(|_, s| !s.user.username.eq_ignore_ascii_case(&username));
+    axum::response::Response::new("OK".into())
+}
+
+// Tag the pooled connection with the signed-in user so triggers and audit
+// columns can read it via SESSION_CONTEXT(N'app_user')
+pub(crate) async fn set_session_user(conn: &mut DbConn, user: &CurrentUser) -> Result<(), AppError> {
+    execute_counted(conn, "EXEC sp_set_session_context @key = N'app_user', @value = ?", &[Some(&user.username)])
+        .await
+        .map(|_| ())
+}
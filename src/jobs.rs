@@ -0,0 +1,456 @@
+// Background jobs: queued report builds with results kept on disk
+
+use super::*;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+// What a background job builds; each kind is one of the report endpoints, written to a file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum JobSpec {
+    // Schedule of accommodation as json, csv or pdf
+    Accommodation {
+        #[serde(default)]
+        format: Option<String>,
+    },
+    Equipment,
+    // Room data sheets for a Room_Id, or every room in an Areaslevel1 or Areaslevel2 area
+    RoomSheets { scope: String, target: String },
+    SnapshotDiff { snapshot: String },
+    // This database's tables against another project database's
+    DatabaseDiff { other: String },
+}
+
+impl JobSpec {
+    pub(crate) fn validate(&self) -> Result<(), AppError> {
+        match self {
+            JobSpec::Accommodation { format } => match format.as_deref().unwrap_or("json") {
+                "json" | "csv" | "pdf" => Ok(()),
+                other => Err(AppError::validation(format!("Unknown report format {}; use json, csv or pdf", other))),
+            },
+            JobSpec::RoomSheets { scope, target } => sheet_scope(scope, target).map(|_| ()),
+            JobSpec::SnapshotDiff { snapshot } => check_snapshot_name(snapshot),
+            JobSpec::DatabaseDiff { other } if other.trim().is_empty() => Err(AppError::validation("Name the database to compare with in other")),
+            JobSpec::Equipment | JobSpec::DatabaseDiff { .. } => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn sheet_scope(scope: &str, id: &str) -> Result<SheetScope, AppError> {
+    match scope {
+        "room" => Ok(SheetScope::Room(id.to_string())),
+        "areaslevel1" => Ok(SheetScope::Areaslevel1(id.to_string())),
+        "areaslevel2" => Ok(SheetScope::Areaslevel2(id.to_string())),
+        other => Err(AppError::validation(format!("Unknown room sheet scope {}; use room, areaslevel1 or areaslevel2", other))),
+    }
+}
+
+// The file a finished job left under data/jobs
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct JobResult {
+    pub(crate) file_name: String,
+    pub(crate) content_type: String,
+    pub(crate) size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Job {
+    pub(crate) id: String,
+    pub(crate) database: String,
+    pub(crate) owner: String,
+    #[serde(flatten)]
+    pub(crate) spec: JobSpec,
+    pub(crate) status: JobStatus,
+    // Percent done, with what the job is doing now
+    pub(crate) progress: u8,
+    pub(crate) message: String,
+    pub(crate) created_at: u64,
+    pub(crate) started_at: Option<u64>,
+    pub(crate) finished_at: Option<u64>,
+    pub(crate) error: Option<String>,
+    pub(crate) result: Option<JobResult>,
+}
+
+impl Job {
+    pub(crate) fn finished(&self) -> bool {
+        matches!(self.status, JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct JobStore {
+    pub(crate) jobs: Vec<Job>,
+}
+
+pub(crate) static JOB_STORE: Lazy<RwLock<JobStore>> = Lazy::new(|| RwLock::new(load_store("jobs.json")));
+// Cancel flags of jobs not yet finished, by job id
+pub(crate) static JOB_CANCEL: Lazy<DashMap<String, Arc<std::sync::atomic::AtomicBool>>> = Lazy::new(DashMap::new);
+// One permit per worker; queued jobs wait here on the runtime instead of holding a thread
+pub(crate) static JOB_SLOTS: Lazy<tokio::sync::Semaphore> = Lazy::new(|| tokio::sync::Semaphore::new(CONFIG.job_workers));
+
+pub(crate) fn new_job_id() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn job_result_path(id: &str) -> std::path::PathBuf {
+    CONFIG.data_dir.join("jobs").join(format!("{}.result", id))
+}
+
+// Handed to the code doing a job's work, so it can report progress and notice a cancel
+#[derive(Clone)]
+pub(crate) struct JobContext {
+    pub(crate) id: String,
+    pub(crate) cancel: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl JobContext {
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    // Called between steps; errors once the job has been cancelled. Progress is
+    // best effort and skipped while another task holds the job store.
+    pub(crate) fn progress(&self, done: usize, total: usize, message: &str) -> Result<(), AppError> {
+        if self.cancelled() {
+            return Err(AppError::conflict("Job cancelled"));
+        }
+        let Ok(mut store) = JOB_STORE.try_write() else {
+            return Ok(());
+        };
+        if let Some(job) = store.jobs.iter_mut().find(|j| j.id == self.id) {
+            job.progress = (done * 100 / total.max(1)).min(100) as u8;
+            job.message = message.to_string();
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn update_job(id: &str, change: impl FnOnce(&mut Job)) {
+    let mut store = JOB_STORE.write().await;
+    if let Some(job) = store.jobs.iter_mut().find(|j| j.id == id) {
+        change(job);
+    }
+    if let Err(e) = save_store("jobs.json", &*store) {
+        tracing::error!("Could not save jobs.json: {e}");
+    }
+}
+
+// What a job read from the database. It becomes the output file only after the
+// connection is back in the pool, on the blocking pool.
+pub(crate) enum JobInput {
+    Accommodation { tables: AreaTables, format: Option<String> },
+    Equipment(EquipmentRollup),
+    RoomSheets(RoomSheetData),
+    SnapshotDiff { snapshot: Snapshot, live: HashMap<String, TableData> },
+    DatabaseDiff { tables: HashMap<String, TableData>, other: String, other_tables: HashMap<String, TableData> },
+}
+
+pub(crate) async fn job_input(job: &Job, ctx: &JobContext) -> Result<JobInput, AppError> {
+    let (db_name, ctx) = (job.database.clone(), ctx.clone());
+    match job.spec.clone() {
+        JobSpec::Accommodation { format } => {
+            ctx.progress(1, 3, "Reading areas and rooms")?;
+            let tables = with_connection(&db_name, |conn| Box::pin(async move { AreaTables::load(conn).await })).await?;
+            ctx.progress(2, 3, "Writing report")?;
+            Ok(JobInput::Accommodation { tables, format })
+        }
+        JobSpec::Equipment => with_connection(&db_name, move |conn| Box::pin(async move {
+            ctx.progress(1, 3, "Counting rooms")?;
+            let counts = query_table(conn, ROOM_COUNT_SQL, &[]).await?;
+            let count = |i: usize| counts.rows.first().and_then(|r| r.get(i)).and_then(|c| c.as_u64()).unwrap_or(0);
+            ctx.progress(2, 3, "Adding up items")?;
+            let items = query_table(conn, EQUIPMENT_ROLLUP_SQL, &[]).await?;
+            let categories = category_totals(&items);
+            Ok(JobInput::Equipment(EquipmentRollup { rooms: count(0), ignored_rooms: count(1), items, categories }))
+        })).await,
+        JobSpec::RoomSheets { scope, target } => {
+            let scope = sheet_scope(&scope, &target)?;
+            let db = db_name.clone();
+            let data = with_connection(&db_name, move |conn| Box::pin(async move {
+                read_room_sheets(conn, &db, &scope, &|done, total| {
+                    ctx.progress(done, total, &format!("Room {} of {}", done + 1, total))
+                }).await
+            })).await?;
+            Ok(JobInput::RoomSheets(data))
+        }
+        JobSpec::SnapshotDiff { snapshot } => {
+            ctx.progress(1, 3, "Reading snapshot")?;
+            let db = db_name.clone();
+            let snapshot = tokio::task::spawn_blocking(move || load_snapshot(&db, &snapshot)).await??;
+            ctx.progress(2, 3, "Reading live tables")?;
+            let live = with_connection(&db_name, |conn| Box::pin(async move { read_live_tables(conn).await })).await?;
+            Ok(JobInput::SnapshotDiff { snapshot, live })
+        }
+        JobSpec::DatabaseDiff { other } => {
+            ctx.progress(0, 2, &format!("Reading {}", db_name))?;
+            let tables = with_connection(&db_name, |conn| Box::pin(async move { read_live_tables(conn).await })).await?;
+            ctx.progress(1, 2, &format!("Reading {}", other))?;
+            let other_tables = with_connection(&other, |conn| Box::pin(async move { read_live_tables(conn).await })).await?;
+            Ok(JobInput::DatabaseDiff { tables, other, other_tables })
+        }
+    }
+}
+
+// Build a job's output from what it read: (file name, content type, bytes)
+pub(crate) fn job_output(db_name: &str, input: JobInput, rules: &RollupRules) -> Result<(String, &'static str, Vec<u8>), AppError> {
+    let base = safe_file_name(db_name);
+    match input {
+        JobInput::Accommodation { tables, format } => {
+            let report = accommodation_report(db_name, &tables, rules);
+            let file_name = format!("{} schedule of accommodation", base);
+            Ok(match format.as_deref().unwrap_or("json") {
+                "csv" => (format!("{}.csv", file_name), "text/csv; charset=utf-8", accommodation_csv(&report).into_bytes()),
+                "pdf" => (format!("{}.pdf", file_name), "application/pdf", accommodation_pdf(&report)?),
+                _ => (format!("{}.json", file_name), "application/json", serde_json::to_vec_pretty(&report).map_err(AppError::internal)?),
+            })
+        }
+        JobInput::Equipment(rollup) => {
+            Ok((format!("{} equipment.json", base), "application/json", serde_json::to_vec_pretty(&rollup).map_err(AppError::internal)?))
+        }
+        JobInput::RoomSheets(data) => {
+            let (file_name, pdf) = render_room_sheets(&data)?;
+            Ok((file_name, "application/pdf", pdf))
+        }
+        JobInput::SnapshotDiff { snapshot, live } => {
+            let diff = snapshot_diff(&snapshot, &live);
+            let file_name = format!("{} {} diff.json", base, safe_file_name(&snapshot.name));
+            Ok((file_name, "application/json", serde_json::to_vec_pretty(&diff).map_err(AppError::internal)?))
+        }
+        JobInput::DatabaseDiff { tables, other, other_tables } => {
+            let diff = database_diff(db_name, &tables, &other, &other_tables);
+            let file_name = format!("{} vs {} diff.json", base, safe_file_name(&other));
+            Ok((file_name, "application/json", serde_json::to_vec_pretty(&diff).map_err(AppError::internal)?))
+        }
+    }
+}
+
+// Render a job's output and write it to its result file
+pub(crate) fn write_job_result(db_name: &str, input: JobInput, rules: &RollupRules, path: &std::path::Path) -> Result<JobResult, AppError> {
+    let (file_name, content_type, bytes) = job_output(db_name, input, rules)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(AppError::internal)?;
+    }
+    std::fs::write(path, &bytes).map_err(AppError::internal)?;
+    Ok(JobResult { file_name, content_type: content_type.to_string(), size: bytes.len() as u64 })
+}
+
+// Wait for a worker slot, run the job and record how it ended
+pub(crate) async fn run_job(job: Job, ctx: JobContext) {
+    let _permit = JOB_SLOTS.acquire().await;
+    // Cancelled while still queued; the cancel handler already recorded it
+    if ctx.cancelled() {
+        JOB_CANCEL.remove(&ctx.id);
+        return;
+    }
+    update_job(&ctx.id, |j| {
+        j.status = JobStatus::Running;
+        j.started_at = Some(unix_now());
+        j.message = "Running".to_string();
+    }).await;
+    let rules = rollup_rules(&job.database).await;
+    let outcome = match job_input(&job, &ctx).await {
+        Ok(input) => {
+            let (db_name, path) = (job.database.clone(), job_result_path(&ctx.id));
+            tokio::task::spawn_blocking(move || write_job_result(&db_name, input, &rules, &path))
+                .await
+                .map_err(AppError::from)
+                .and_then(|r| r)
+        }
+        Err(e) => Err(e),
+    };
+    let cancelled = ctx.cancelled();
+    JOB_CANCEL.remove(&ctx.id);
+    update_job(&ctx.id, |j| {
+        j.finished_at = Some(unix_now());
+        match outcome {
+            _ if cancelled => {
+                j.status = JobStatus::Cancelled;
+                j.message = "Cancelled".to_string();
+            }
+            Ok(result) => {
+                j.status = JobStatus::Succeeded;
+                j.progress = 100;
+                j.message = "Done".to_string();
+                j.result = Some(result);
+            }
+            Err(e) => {
+                j.status = JobStatus::Failed;
+                j.message = "Failed".to_string();
+                j.error = Some(e.message);
+            }
+        }
+    }).await;
+    if cancelled {
+        let path = job_result_path(&ctx.id);
+        let _ = tokio::task::spawn_blocking(move || std::fs::remove_file(path)).await;
+    }
+}
+
+// Forget finished jobs past the retention period, with their result files
+pub(crate) async fn prune_jobs() {
+    let cutoff = unix_now().saturating_sub(CONFIG.job_retention_hours * 3600);
+    let mut store = JOB_STORE.write().await;
+    let (expired, kept): (Vec<Job>, Vec<Job>) = std::mem::take(&mut store.jobs)
+        .into_iter()
+        .partition(|j| j.finished() && j.finished_at.unwrap_or(0) < cutoff);
+    store.jobs = kept;
+    if expired.is_empty() {
+        return;
+    }
+    if let Err(e) = save_store("jobs.json", &*store) {
+        tracing::error!("Could not save jobs.json: {e}");
+    }
+    drop(store);
+    let paths: Vec<_> = expired.iter().map(|j| job_result_path(&j.id)).collect();
+    let _ = tokio::task::spawn_blocking(move || paths.into_iter().for_each(|p| { let _ = std::fs::remove_file(p); })).await;
+}
+
+// Jobs live in this process; any still queued or running at startup were cut off by a restart
+pub(crate) async fn recover_jobs() {
+    let mut store = JOB_STORE.write().await;
+    let mut changed = false;
+    for job in store.jobs.iter_mut().filter(|j| !j.finished()) {
+        job.status = JobStatus::Failed;
+        job.message = "Failed".to_string();
+        job.error = Some("Interrupted by a server restart".to_string());
+        job.finished_at = Some(unix_now());
+        changed = true;
+    }
+    if changed {
+        if let Err(e) = save_store("jobs.json", &*store) {
+            tracing::error!("Could not save jobs.json: {e}");
+        }
+    }
+}
+
+// Jobs are visible to the user who started them and to administrators
+pub(crate) async fn visible_job(job_id: &str, user: &CurrentUser) -> Result<Job, AppError> {
+    let job = JOB_STORE.read().await.jobs.iter().find(|j| j.id == job_id).cloned();
+    match job {
+        Some(job) if job.owner.eq_ignore_ascii_case(&user.username) || is_admin(&user.username).await => Ok(job),
+        _ => Err(AppError::not_found(format!("Job {} not found", job_id))),
+    }
+}
+
+pub(crate) async fn jobs_page(Path(_db_name): Path<String>) -> impl IntoResponse {
+    Html(include_str!("../static/jobs.html"))
+}
+
+// Handler to queue a job on a database; answers at once with the job, 202 Accepted
+pub(crate) async fn start_job(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(spec): Json<JobSpec>) -> axum::response::Response {
+    if let Err(e) = spec.validate() {
+        return e.into_response();
+    }
+    // The route only checked this database
+    if let JobSpec::DatabaseDiff { other } = &spec {
+        if !user_roles(&user.username, other).await.iter().any(|r| role_allows(*r, Access::View)) {
+            return AppError::new(ErrorKind::Forbidden, format!("{} does not have {:?} permission on {}", user.username, Access::View, other)).into_response();
+        }
+    }
+    prune_jobs().await;
+    let job = Job {
+        id: new_job_id(),
+        database: db_name,
+        owner: user.username,
+        spec,
+        status: JobStatus::Queued,
+        progress: 0,
+        message: "Queued".to_string(),
+        created_at: unix_now(),
+        started_at: None,
+        finished_at: None,
+        error: None,
+        result: None,
+    };
+    let ctx = JobContext { id: job.id.clone(), cancel: Arc::new(std::sync::atomic::AtomicBool::new(false)) };
+    {
+        let mut store = JOB_STORE.write().await;
+        store.jobs.push(job.clone());
+        if let Err(e) = save_store("jobs.json", &*store) {
+            return AppError::internal(e).into_response();
+        }
+    }
+    JOB_CANCEL.insert(job.id.clone(), ctx.cancel.clone());
+    tokio::spawn(run_job(job.clone(), ctx));
+    (axum::http::StatusCode::ACCEPTED, Json(job)).into_response()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct JobListQuery {
+    pub(crate) database: Option<String>,
+}
+
+// Handler listing the user's jobs (every job for administrators), newest first
+pub(crate) async fn list_jobs(Extension(user): Extension<CurrentUser>, Query(query): Query<JobListQuery>) -> axum::response::Response {
+    let admin = is_admin(&user.username).await;
+    let store = JOB_STORE.read().await;
+    let mut jobs: Vec<&Job> = store.jobs.iter()
+        .filter(|j| admin || j.owner.eq_ignore_ascii_case(&user.username))
+        .filter(|j| query.database.as_ref().is_none_or(|d| j.database.eq_ignore_ascii_case(d)))
+        .collect();
+    jobs.sort_by_key(|j| std::cmp::Reverse(j.created_at));
+    Json(jobs).into_response()
+}
+
+pub(crate) async fn get_job(Path(job_id): Path<String>, Extension(user): Extension<CurrentUser>) -> axum::response::Response {
+    match visible_job(&job_id, &user).await {
+        Ok(job) => Json(job).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handler to download what a finished job produced
+pub(crate) async fn download_job_result(Path(job_id): Path<String>, Extension(user): Extension<CurrentUser>) -> axum::response::Response {
+    let job = match visible_job(&job_id, &user).await {
+        Ok(job) => job,
+        Err(e) => return e.into_response(),
+    };
+    let Some(result) = job.result.filter(|_| job.status == JobStatus::Succeeded) else {
+        return AppError::conflict(format!("Job {} has no result yet ({:?})", job_id, job.status)).into_response();
+    };
+    let path = job_result_path(&job_id);
+    match tokio::task::spawn_blocking(move || std::fs::read(path)).await {
+        Ok(Ok(bytes)) => (
+            [
+                (axum::http::header::CONTENT_TYPE, result.content_type),
+                (axum::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", result.file_name)),
+            ],
+            bytes,
+        ).into_response(),
+        _ => AppError::not_found(format!("Result of job {} is no longer available", job_id)).into_response(),
+    }
+}
+
+// Handler to cancel a job: a queued job never starts, a running one stops at its next step
+pub(crate) async fn cancel_job(Path(job_id): Path<String>, Extension(user): Extension<CurrentUser>) -> axum::response::Response {
+    let job = match visible_job(&job_id, &user).await {
+        Ok(job) => job,
+        Err(e) => return e.into_response(),
+    };
+    if job.finished() {
+        return AppError::conflict(format!("Job {} has already finished", job_id)).into_response();
+    }
+    if let Some(flag) = JOB_CANCEL.get(&job_id) {
+        flag.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    update_job(&job_id, |j| match j.status {
+        JobStatus::Queued => {
+            j.status = JobStatus::Cancelled;
+            j.message = "Cancelled".to_string();
+            j.finished_at = Some(unix_now());
+        }
+        _ => j.message = "Cancelling".to_string(),
+    }).await;
+    get_job(Path(job_id), Extension(user)).await
+}
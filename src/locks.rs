@@ -0,0 +1,128 @@
+// Read-only locks on issued schedules
+
+use super::*;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ProjectLock {
+    pub(crate) database: String,
+    pub(crate) reason: String,
+    pub(crate) locked_by: String,
+    pub(crate) locked_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct LockEvent {
+    pub(crate) database: String,
+    pub(crate) action: String,
+    pub(crate) reason: String,
+    pub(crate) user: String,
+    pub(crate) at: u64,
+}
+
+// Issued databases are frozen here; keyed by lower-cased database name
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct LockStore {
+    pub(crate) locks: HashMap<String, ProjectLock>,
+    pub(crate) history: Vec<LockEvent>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LockRequest {
+    pub(crate) database: String,
+    #[serde(default)]
+    pub(crate) reason: String,
+}
+
+pub(crate) static LOCK_STORE: Lazy<RwLock<LockStore>> = Lazy::new(|| RwLock::new(load_store("locks.json")));
+
+pub(crate) async fn project_lock(db_name: &str) -> Option<ProjectLock> {
+    LOCK_STORE.read().await.locks.get(&db_name.to_lowercase()).cloned()
+}
+
+// Whether a lock freezes a POST route, as marked in POST_ROUTES. A route without an
+// entry counts as changing the project; require_permission refuses it anyway.
+pub(crate) fn is_mutation_route(route: &str) -> bool {
+    POST_ROUTES.iter().find(|r| r.pattern == route).is_none_or(|r| r.mutates)
+}
+
+// Middleware: answer 423 Locked for edits to a locked database
+pub(crate) async fn reject_if_locked(Path(params): Path<HashMap<String, String>>, req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let route = req.extensions().get::<axum::extract::MatchedPath>().map(|m| m.as_str().to_string()).unwrap_or_default();
+    if req.method() != axum::http::Method::GET && is_mutation_route(&route) {
+        let db_name = params.get("db_name").cloned().unwrap_or_default();
+        if let Some(lock) = project_lock(&db_name).await {
+            let msg = format!("{} is locked (read-only) by {}: {}", lock.database, lock.locked_by, lock.reason);
+            let details = serde_json::json!({ "locked_by": lock.locked_by, "locked_at": lock.locked_at, "reason": lock.reason });
+            return AppError::new(ErrorKind::Locked, msg).with_details(details).into_response();
+        }
+    }
+    next.run(req).await
+}
+
+pub(crate) async fn get_lock_status(Path(db_name): Path<String>) -> axum::response::Response {
+    match project_lock(&db_name).await {
+        Some(lock) => Json(serde_json::json!({
+            "locked": true,
+            "reason": lock.reason,
+            "locked_by": lock.locked_by,
+            "locked_at": lock.locked_at,
+        })).into_response(),
+        None => Json(serde_json::json!({ "locked": false })).into_response(),
+    }
+}
+
+pub(crate) async fn admin_list_locks() -> axum::response::Response {
+    let store = LOCK_STORE.read().await;
+    let locks: Vec<&ProjectLock> = store.locks.values().collect();
+    Json(serde_json::json!({ "locks": locks, "history": store.history })).into_response()
+}
+
+pub(crate) async fn admin_lock_database(Extension(user): Extension<CurrentUser>, Json(payload): Json<LockRequest>) -> axum::response::Response {
+    let LockRequest { database, reason } = payload;
+    if database.trim().is_empty() || reason.trim().is_empty() {
+        return AppError::validation("database and reason are required").into_response();
+    }
+    let mut store = LOCK_STORE.write().await;
+    let now = unix_now();
+    store.locks.insert(database.to_lowercase(), ProjectLock {
+        database: database.clone(),
+        reason: reason.clone(),
+        locked_by: user.username.clone(),
+        locked_at: now,
+    });
+    store.history.push(LockEvent { database, action: "lock".to_string(), reason, user: user.username, at: now });
+    match save_store("locks.json", &*store) {
+        Ok(()) => axum::response::Response::new("OK".into()),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+pub(crate) async fn admin_unlock_database(Extension(user): Extension<CurrentUser>, Json(payload): Json<LockRequest>) -> axum::response::Response {
+    let LockRequest { database, reason } = payload;
+    let mut store = LOCK_STORE.write().await;
+    if store.locks.remove(&database.to_lowercase()).is_none() {
+        return AppError::not_found(format!("{database} is not locked")).into_response();
+    }
+    store.history.push(LockEvent { database, action: "unlock".to_string(), reason, user: user.username, at: unix_now() });
+    match save_store("locks.json", &*store) {
+        Ok(()) => axum::response::Response::new("OK".into()),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_freeze_only_routes_marked_as_mutating() {
+        for route in ["areaslevel1/add", "item_schedule/update_batch", "table/:table/delete", "integrity/fix", "rollup_rules/update", "snapshots/delete"] {
+            assert!(is_mutation_route(&format!("/api/db/:db_name/{route}")), "{route}");
+        }
+        for route in ["editing", "snapshots/create", "jobs/start"] {
+            assert!(!is_mutation_route(&format!("/api/db/:db_name/{route}")), "{route}");
+        }
+        // A route nobody listed is treated as a change
+        assert!(is_mutation_route("/api/db/:db_name/unknown"));
+    }
+}
@@ -2,37 +2,233 @@ use axum::{routing::get, Router, response::{Html, IntoResponse}, Json};
 use serde::Serialize;
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
-use odbc_api::{Environment, Error, Cursor, buffers::TextRowSet, ResultSetMetadata};
-use axum::extract::Path;
+#[cfg(feature = "odbc")]
+use odbc_api::{Cursor, IntoParameter, ResultSetMetadata};
+use axum::extract::{Path, Query};
 use serde::Deserialize;
-use std::ffi::CString;
 use once_cell::sync::Lazy;
 use tokio::sync::RwLock;
 use std::time::{Duration, Instant};
 use bb8::{Pool, ManageConnection};
 use axum::extract::Extension;
+#[cfg(feature = "odbc")]
 use odbc_api::Connection;
 use async_trait::async_trait;
 use dashmap::DashMap;
 use std::sync::Arc;
-use webbrowser;
+use std::collections::HashMap;
+
+#[cfg(not(any(feature = "odbc", feature = "tiberius")))]
+compile_error!("enable the odbc or tiberius feature, or both, to pick the SQL Server drivers built in");
+
+mod auth;
+mod jobs;
+mod locks;
+mod metrics;
+mod pool;
+mod report;
+mod roles;
+mod snapshots;
+
+use auth::*;
+use jobs::*;
+use locks::*;
+use metrics::*;
+use pool::*;
+use report::*;
+use roles::*;
+use snapshots::*;
 
 #[derive(Serialize)]
 struct DatabaseList {
     databases: Vec<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 struct TableData {
     columns: Vec<String>,
-    rows: Vec<Vec<String>>,
+    // Cells as the database typed them: numbers, strings, or null for NULL
+    rows: Vec<Vec<serde_json::Value>>,
+    // Version token per row, sent back as `expected_version` on updates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    versions: Vec<String>,
+}
+
+// Every handler failure is one of these; each maps to one HTTP status and `code`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    Locked,
+    Validation,
+    TooManyRequests,
+    Unavailable,
+    Internal,
+}
+
+impl ErrorKind {
+    fn status(self) -> axum::http::StatusCode {
+        match self {
+            ErrorKind::Unauthorized => axum::http::StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => axum::http::StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => axum::http::StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => axum::http::StatusCode::CONFLICT,
+            ErrorKind::Locked => axum::http::StatusCode::LOCKED,
+            ErrorKind::Validation => axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::TooManyRequests => axum::http::StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Unavailable => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Internal => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Forbidden => "forbidden",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Locked => "locked",
+            ErrorKind::Validation => "validation",
+            ErrorKind::TooManyRequests => "too_many_requests",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
+
+// Sent to the client as JSON `{code, message, details}`
+#[derive(Debug)]
+struct AppError {
+    kind: ErrorKind,
+    message: String,
+    details: serde_json::Value,
+}
+
+impl AppError {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> AppError {
+        AppError { kind, message: message.into(), details: serde_json::Value::Null }
+    }
+
+    fn not_found(message: impl Into<String>) -> AppError {
+        AppError::new(ErrorKind::NotFound, message)
+    }
+
+    fn validation(message: impl Into<String>) -> AppError {
+        AppError::new(ErrorKind::Validation, message)
+    }
+
+    fn conflict(message: impl Into<String>) -> AppError {
+        AppError::new(ErrorKind::Conflict, message)
+    }
+
+    // Log the real cause, show the client only a generic message
+    fn internal(cause: impl std::fmt::Display) -> AppError {
+        tracing::error!(cause = %cause, "internal error");
+        AppError::new(ErrorKind::Internal, "Internal server error")
+    }
+
+    fn with_details(mut self, details: serde_json::Value) -> AppError {
+        self.details = details;
+        self
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let body = serde_json::json!({ "code": self.kind.code(), "message": self.message, "details": self.details });
+        (self.kind.status(), Json(body)).into_response()
+    }
+}
+
+// Classify an ODBC message by its SQLSTATE; the raw text only goes to the log
+#[cfg(feature = "odbc")]
+fn driver_error(raw: &str) -> AppError {
+    let field = |name: &str| raw.split(name).nth(1).map(|s| s.chars().take_while(|c| c.is_ascii_alphanumeric()).collect::<String>()).unwrap_or_default();
+    let state = field("State: ");
+    let native = field("Native error: ");
+    tracing::error!(sqlstate = %state, native_error = %native, diagnostics = raw, "database error");
+    let (kind, message) = classify_driver_error(&state, &native);
+    let details = if state.is_empty() { serde_json::Value::Null } else { serde_json::json!({ "sqlstate": state }) };
+    AppError::new(kind, message).with_details(details)
+}
+
+fn classify_driver_error(state: &str, native: &str) -> (ErrorKind, &'static str) {
+    match (state, native) {
+        ("23000", "2627") | ("23000", "2601") => (ErrorKind::Conflict, "A row with this key already exists"),
+        ("23000", "547") => (ErrorKind::Conflict, "The change would break a reference between tables"),
+        ("23000", "515") => (ErrorKind::Validation, "A required column was left empty"),
+        ("23000", _) => (ErrorKind::Conflict, "The change violates a database constraint"),
+        (s, _) if s.starts_with("22") => (ErrorKind::Validation, "A value does not fit the column's type or size"),
+        (s, _) if s.starts_with("08") || s == "HYT00" || s == "HYT01" => (ErrorKind::Unavailable, "The database server is not responding"),
+        _ => (ErrorKind::Internal, "The database reported an error"),
+    }
+}
+
+#[cfg(feature = "odbc")]
+impl From<odbc_api::Error> for AppError {
+    fn from(e: odbc_api::Error) -> AppError {
+        driver_error(&e.to_string())
+    }
+}
+
+// tiberius reports only the server's error number, so the common ones are
+// mapped onto the SQLSTATE class ODBC would have given them
+#[cfg(feature = "tiberius")]
+impl From<tiberius::error::Error> for AppError {
+    fn from(e: tiberius::error::Error) -> AppError {
+        // The Debug form carries the server's state, class, procedure and line
+        tracing::error!(native_error = e.code(), diagnostics = ?e, "database error: {e}");
+        let state = match (&e, e.code()) {
+            (tiberius::error::Error::Io { .. }, _) => "08S01",
+            (_, Some(2627 | 2601 | 547 | 515)) => "23000",
+            (_, Some(220 | 232 | 241 | 242 | 245 | 2628 | 8114 | 8115 | 8152)) => "22000",
+            _ => "",
+        };
+        let native = e.code().map(|c| c.to_string()).unwrap_or_default();
+        let (kind, message) = classify_driver_error(state, &native);
+        let details = match e.code() {
+            Some(code) => serde_json::json!({ "native_error": code }),
+            None => serde_json::Value::Null,
+        };
+        AppError::new(kind, message).with_details(details)
+    }
+}
+
+impl From<bb8::RunError<String>> for AppError {
+    fn from(e: bb8::RunError<String>) -> AppError {
+        match e {
+            // With every slot taken, the pool's connect attempts are what timed out
+            bb8::RunError::TimedOut if CONNECTION_SLOTS.available_permits() == 0 => AppError::new(ErrorKind::Unavailable, connection_limit_message()),
+            bb8::RunError::TimedOut => AppError::new(ErrorKind::Unavailable, "Timed out waiting for a database connection"),
+            bb8::RunError::User(e) => {
+                tracing::error!(error = %e, "database connection failed");
+                AppError::new(ErrorKind::Unavailable, "Could not connect to the database")
+            }
+        }
+    }
+}
+
+impl From<std::ffi::NulError> for AppError {
+    fn from(_: std::ffi::NulError) -> AppError {
+        AppError::validation("Values may not contain NUL characters")
+    }
+}
+
+impl From<tokio::task::JoinError> for AppError {
+    fn from(e: tokio::task::JoinError) -> AppError {
+        AppError::internal(e)
+    }
 }
 
 #[derive(Deserialize)]
 struct UpdateCell {
     parent_area: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -44,7 +240,9 @@ struct DeleteRow {
 struct UpdateAreaslevel2Cell {
     arealevel_id: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -56,7 +254,9 @@ struct DeleteAreaslevel2Row {
 struct UpdateAreaslevel1Cell {
     arealevel_id: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -68,7 +268,9 @@ struct DeleteAreaslevel1Row {
 struct UpdateRoomScheduleCell {
     room_id: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -81,17 +283,23 @@ struct DeleteRoomScheduleRow {
 struct UpdateItemScheduleCell {
     item_schedule_id: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
+// Fields are named after the Item_Schedule columns they fill
 #[derive(Deserialize)]
+#[allow(non_snake_case)]
 struct AddItemScheduleRow {
     Item_Ref: String,
     Room_Code: String,
-    Ignore_flag: String,
+    #[serde(default)]
+    Ignore_flag: Option<String>,
     Qty_New: i64,
     Qty_Trans: i64,
-    Notes: String,
+    #[serde(default)]
+    Notes: Option<String>,
     instance_variant: i64,
 }
 
@@ -102,79 +310,66 @@ struct DeleteItemScheduleRow {
 
 async fn add_item_schedule_row(
     Path(db_name): Path<String>,
+    Extension(user): Extension<CurrentUser>,
     Json(payload): Json<AddItemScheduleRow>
 ) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        let payload = payload;
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let sql = "INSERT INTO Item_Schedule ([Item_Ref], [Room_Code], [Ignore_flag], [Qty_New], [Qty_Trans], [Notes], [instance_variant]) VALUES (?, ?, ?, ?, ?, ?, ?)";
-            let qty_new = payload.Qty_New.to_string();
-            let qty_trans = payload.Qty_Trans.to_string();
-            let instance_variant = payload.instance_variant.to_string();
-            let item_ref_cstr = CString::new(payload.Item_Ref).map_err(|e| e.to_string())?;
-            let room_code_cstr = CString::new(payload.Room_Code).map_err(|e| e.to_string())?;
-            let ignore_flag_cstr = CString::new(payload.Ignore_flag).map_err(|e| e.to_string())?;
-            let notes_cstr = CString::new(payload.Notes).map_err(|e| e.to_string())?;
-            let qty_new_cstr = CString::new(qty_new).map_err(|e| e.to_string())?;
-            let qty_trans_cstr = CString::new(qty_trans).map_err(|e| e.to_string())?;
-            let instance_variant_cstr = CString::new(instance_variant).map_err(|e| e.to_string())?;
-            let exec_result = conn.execute(
-                sql,
-                (
-                    &item_ref_cstr,
-                    &room_code_cstr,
-                    &ignore_flag_cstr,
-                    &qty_new_cstr,
-                    &qty_trans_cstr,
-                    &notes_cstr,
-                    &instance_variant_cstr,
-                ),
-            );
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
+    let change = ChangeEvent { room_code: Some(payload.Room_Code.clone()), ..ChangeEvent::new("add", "Item_Schedule", &user) };
+    let warning = match reference_warning(&db_name, "Item_Schedule", "Item_Ref", &payload.Item_Ref).await {
+        Ok(w) => w,
+        Err(e) => return e.into_response(),
+    };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        if payload.instance_variant != 0 {
+            check_room_instance(conn, payload.instance_variant, &payload.Room_Code).await?;
         }
-    }).await;
+        let columns: Vec<String> = ["Item_Ref", "Room_Code", "Ignore_flag", "Qty_New", "Qty_Trans", "Notes", "instance_variant"]
+            .iter().map(|c| c.to_string()).collect();
+        let values = [
+            serde_json::json!(payload.Item_Ref),
+            serde_json::json!(payload.Room_Code),
+            serde_json::json!(payload.Ignore_flag),
+            serde_json::json!(payload.Qty_New),
+            serde_json::json!(payload.Qty_Trans),
+            serde_json::json!(payload.Notes),
+            serde_json::json!(payload.instance_variant),
+        ];
+        let values = values.iter().map(cell_param).collect::<Result<Vec<_>, _>>()?;
+        insert_returning(conn, "Item_Schedule", "Item_schedule_id", &columns, &values).await
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(row) => {
+            publish_change(&db_name, ChangeEvent { row: Some(serde_json::Value::Object(row.clone())), ..change });
+            with_reference_warning(Json(row).into_response(), warning)
+        }
+        Err(e) => e.into_response(),
     }
 }
 
 async fn delete_item_schedule_row(
     Path(db_name): Path<String>,
+    Extension(user): Extension<CurrentUser>,
     Json(payload): Json<DeleteItemScheduleRow>
 ) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
+    let change = ChangeEvent { key: Some(payload.item_schedule_id.to_string()), ..ChangeEvent::new("delete", "Item_Schedule", &user) };
+    let result = with_connection(&db_name, {
         let item_schedule_id = payload.item_schedule_id;
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
+        move |conn| Box::pin(async move {
+            set_session_user(conn, &user).await?;
             let sql = "DELETE FROM Item_Schedule WHERE [Item_schedule_id] = ?";
-            let id_cstr = CString::new(item_schedule_id.to_string()).map_err(|e| e.to_string())?;
-            let exec_result = conn.execute(sql, (&id_cstr,));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
+            let id = item_schedule_id.to_string();
+            if execute_counted(conn, sql, &[Some(id.as_str())]).await? == 0 {
+                return Err(missing_row("Item_Schedule", "Item_schedule_id", &item_schedule_id.to_string()));
             }
-        }
+            Ok(())
+        })
     }).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(()) => {
+            publish_change(&db_name, change);
+            axum::response::Response::new("OK".into())
+        }
+        Err(e) => e.into_response(),
     }
 }
 
@@ -184,95 +379,33 @@ struct CachedDatabases {
 }
 
 static DATABASE_CACHE: Lazy<RwLock<Option<CachedDatabases>>> = Lazy::new(|| RwLock::new(None));
-static POOL_CACHE: once_cell::sync::Lazy<DashMap<String, Arc<Pool<OdbcManager>>>> = once_cell::sync::Lazy::new(|| DashMap::new());
-
-// Custom ODBC connection manager for bb8
-#[derive(Clone, Debug)]
-struct OdbcManager {
-    conn_str: String,
-}
-
-#[async_trait]
-impl ManageConnection for OdbcManager {
-    type Connection = Connection<'static>;
-    type Error = String;
-
-    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        static ENV: Lazy<odbc_api::Environment> = Lazy::new(|| {
-            unsafe { odbc_api::Environment::new().expect("Failed to create ODBC Environment") }
-        });
-        ENV.connect_with_connection_string(&self.conn_str, odbc_api::ConnectionOptions::default())
-            .map_err(|e| e.to_string())
-    }
-
-    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        conn.execute("SELECT 1", ()).map(|_| ()).map_err(|e| e.to_string())
-    }
-
-    fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
-        false
-    }
-}
-
-type DbPool = Pool<OdbcManager>;
-
 async fn index() -> impl IntoResponse {
     Html(include_str!("../static/index.html"))
 }
 
-async fn get_databases() -> axum::response::Response {
+async fn get_databases(Extension(user): Extension<CurrentUser>) -> axum::response::Response {
     // Check cache first
     {
         let cache = DATABASE_CACHE.read().await;
         if let Some(cached) = &*cache {
             if cached.last_updated.elapsed() < Duration::from_secs(60) {
-                let dbs = DatabaseList { databases: cached.data.clone() };
+                METRICS.database_cache_hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let dbs = DatabaseList { databases: visible_databases(&user, &cached.data).await };
                 return Json(dbs).into_response();
             }
         }
     }
-    // Use pooled connection
-    let result = tokio::task::spawn_blocking({
-        let pool = POOL_CACHE.get("master").unwrap().clone(); // Assuming 'master' is the default or first DB
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let query = "SELECT name FROM sys.databases";
-            let mut dbs = Vec::new();
-            let cursor = match conn.execute(query, ()) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                let batch_size = 32;
-                let mut buffers = match TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        if let Some(name) = batch.at(0, row_idx) {
-                            if let Ok(name_str) = std::str::from_utf8(name) {
-                                dbs.push(name_str.to_string());
-                            }
-                        }
-                    }
-                }
-            }
-            Ok(dbs)
-        }
-    }).await;
+    METRICS.database_cache_misses.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let result = with_connection("master", |conn| Box::pin(async move {
+        let mut dbs = Vec::new();
+        for_each_row(conn, "SELECT name FROM sys.databases", &[], |row| {
+            dbs.extend(row.first().map(|name| cell_text(name).into_owned()));
+            Ok(())
+        }).await?;
+        Ok(dbs)
+    })).await;
     match result {
-        Ok(Ok(dbs)) => {
+        Ok(dbs) => {
             // Update cache
             {
                 let mut cache = DATABASE_CACHE.write().await;
@@ -281,974 +414,830 @@ async fn get_databases() -> axum::response::Response {
                     last_updated: Instant::now(),
                 });
             }
-            let dbs = DatabaseList { databases: dbs };
+            let dbs = DatabaseList { databases: visible_databases(&user, &dbs).await };
             Json(dbs).into_response()
         }
-        Ok(Err(e)) => {
-            let msg = format!("Failed to fetch databases: {e}");
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
+        Err(e) => e.into_response(),
+    }
+}
+
+// Only list databases the user holds at least one role on
+async fn visible_databases(user: &CurrentUser, all: &[String]) -> Vec<String> {
+    let store = GRANT_STORE.read().await;
+    let mine: Vec<&Grant> = store.grants.iter().filter(|g| g.username.eq_ignore_ascii_case(&user.username)).collect();
+    if mine.iter().any(|g| g.database == "*") {
+        return all.to_vec();
+    }
+    all.iter().filter(|db| mine.iter().any(|g| g.database.eq_ignore_ascii_case(db))).cloned().collect()
+}
+
+async fn db_control_space(Path(_db_name): Path<String>) -> impl IntoResponse {
+    Html(include_str!("../static/db.html"))
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+struct AreaTotal {
+    // Net: the rooms' own areas
+    area: f64,
+    // Net plus the circulation uplift of this level and the levels below
+    gross: f64,
+    rooms: u64,
+}
+
+impl AreaTotal {
+    fn room(area: f64) -> AreaTotal {
+        AreaTotal { area, gross: area, rooms: 1 }
+    }
+
+    fn add(&mut self, other: AreaTotal) {
+        self.area += other.area;
+        self.gross += other.gross;
+        self.rooms += other.rooms;
+    }
+
+    fn uplift(mut self, percent: f64) -> AreaTotal {
+        self.gross *= 1.0 + percent / 100.0;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum RoundingMode {
+    // Round down, as the area pages always have
+    #[default]
+    Floor,
+    Ceil,
+    Nearest,
+    None,
+}
+
+// Planning/circulation uplift in percent, applied to the total of each level
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+struct LevelUplift {
+    level1: f64,
+    level2: f64,
+    level3: f64,
+}
+
+// How one project adds up its room areas
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct RollupRules {
+    // Leave rooms with Ignore_Flag set out of the area totals
+    exclude_ignored: bool,
+    rounding: RoundingMode,
+    // Decimal places kept by `rounding`
+    precision: u32,
+    uplift: LevelUplift,
+}
+
+impl RollupRules {
+    fn validate(&self) -> Result<(), AppError> {
+        if self.precision > 6 {
+            return Err(AppError::validation("precision must be between 0 and 6"));
+        }
+        let uplift = [self.uplift.level1, self.uplift.level2, self.uplift.level3];
+        if uplift.iter().any(|u| !u.is_finite() || *u < 0.0 || *u > 1000.0) {
+            return Err(AppError::validation("uplift percentages must be between 0 and 1000"));
         }
-        Err(e) => {
-            let msg = format!("Task join error: {e}");
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
+        Ok(())
+    }
+
+    fn round(&self, value: f64) -> f64 {
+        let scale = 10f64.powi(self.precision as i32);
+        match self.rounding {
+            RoundingMode::Floor => (value * scale).floor() / scale,
+            RoundingMode::Ceil => (value * scale).ceil() / scale,
+            RoundingMode::Nearest => (value * scale).round() / scale,
+            RoundingMode::None => value,
+        }
+    }
+
+    // A rounded total as text, e.g. "123" with the default floor to 0 places
+    fn format(&self, value: f64) -> String {
+        match self.rounding {
+            RoundingMode::None => format!("{:.2}", value),
+            _ => format!("{:.*}", self.precision as usize, self.round(value)),
+        }
+    }
+
+    // A rounded total as a JSON number, with the places `format` would show
+    fn value(&self, value: f64) -> serde_json::Value {
+        match self.rounding {
+            RoundingMode::None => json_number((value * 100.0).round() / 100.0),
+            _ => json_number(self.round(value)),
+        }
+    }
+
+    fn uplift(&self, level: usize) -> f64 {
+        match level {
+            1 => self.uplift.level1,
+            2 => self.uplift.level2,
+            _ => self.uplift.level3,
         }
     }
 }
 
-async fn db_control_space(Path(db_name): Path<String>) -> impl IntoResponse {
-    Html(include_str!("../static/db.html"))
+// Rollup rules per project; keyed by lower-cased database name
+#[derive(Serialize, Deserialize, Default)]
+struct RollupStore {
+    projects: HashMap<String, RollupRules>,
 }
 
-async fn get_areaslevel3(Path(db_name): Path<String>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let query = r#"
-                SELECT a3.[ArealevelID], a3.[ParentArea], a3.[AreaDescription],
-                    FLOOR(ISNULL((
-                        SELECT SUM(CAST(r.[Area] AS FLOAT))
-                        FROM Room_Schedule r
-                        WHERE r.[ParentArea] IN (
-                            SELECT al1.[ArealevelID]
-                            FROM Areaslevel1 al1
-                            WHERE al1.[ParentArea] IN (
-                                SELECT a2.[ArealevelID]
-                                FROM Areaslevel2 a2
-                                WHERE a2.[ParentArea] = a3.[ArealevelID]
-                            )
-                        )
-                    ), 0)) AS [Area]
-                FROM Areaslevel3 a3
-            "#;
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, ()) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
+static ROLLUP_STORE: Lazy<RwLock<RollupStore>> = Lazy::new(|| RwLock::new(load_store("rollup_rules.json")));
+
+async fn rollup_rules(db_name: &str) -> RollupRules {
+    ROLLUP_STORE.read().await.projects.get(&db_name.to_lowercase()).cloned().unwrap_or_default()
+}
+
+async fn get_rollup_rules(Path(db_name): Path<String>) -> axum::response::Response {
+    Json(rollup_rules(&db_name).await).into_response()
+}
+
+async fn update_rollup_rules(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(rules): Json<RollupRules>) -> axum::response::Response {
+    if let Err(e) = rules.validate() {
+        return e.into_response();
+    }
+    let mut store = ROLLUP_STORE.write().await;
+    store.projects.insert(db_name.to_lowercase(), rules.clone());
+    if let Err(e) = save_store("rollup_rules.json", &*store) {
+        return AppError::internal(e).into_response();
+    }
+    // Every area total on open pages may have changed
+    publish_change(&db_name, ChangeEvent::new("resync", "Areaslevel3", &user));
+    Json(rules).into_response()
+}
+
+// Page for a project's rollup rules
+async fn rollup_rules_page(Path(_db_name): Path<String>) -> impl IntoResponse {
+    Html(include_str!("../static/rollup_rules.html"))
+}
+
+// The area hierarchy and its rooms, read whole so totals are rolled up in one place
+struct AreaTables {
+    a3: TableData,
+    a2: TableData,
+    a1: TableData,
+    rooms: TableData,
+}
+
+impl AreaTables {
+    async fn load(conn: &mut DbConn) -> Result<AreaTables, AppError> {
+        Ok(AreaTables {
+            a3: query_table(conn, "SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel3", &[]).await?,
+            a2: query_table(conn, "SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel2", &[]).await?,
+            a1: query_table(conn, "SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel1", &[]).await?,
+            rooms: query_table(conn, ROOM_SCHEDULE_SELECT, &[]).await?,
+        })
+    }
+
+    fn rollup<'a>(&'a self, rules: &'a RollupRules) -> AreaRollup<'a> {
+        AreaRollup::new([&self.a3, &self.a2, &self.a1], &self.rooms, rules)
+    }
+}
+
+// Ignore flags hold 1/0, Y/N, Yes/No or True/False depending on who filled them in
+macro_rules! flag_set {
+    ($col:literal) => {
+        concat!("(UPPER(LTRIM(RTRIM(ISNULL(CONVERT(nvarchar(20), ", $col, "), '')))) NOT IN ('', '0', 'N', 'NO', 'FALSE'))")
+    };
+}
+pub(crate) use flag_set;
+
+// Just what one Areaslevel page needs: the areas at its level under its parent, the
+// levels beneath them, and the rooms in those areas, so opening a page does not read
+// every room in the project. Rooms are summed by AreaRollup, as for reports and snapshots.
+struct AreaLevelTables {
+    // Areaslevel3, Areaslevel2, Areaslevel1; the levels above the page are left empty
+    levels: [TableData; 3],
+    rooms: TableData,
+}
+
+impl AreaLevelTables {
+    // `level` is 3, 2 or 1, as in the table names
+    async fn load(conn: &mut DbConn, level: usize, parent: Option<&str>) -> Result<AreaLevelTables, AppError> {
+        let params: Vec<Option<&str>> = parent.map(Some).into_iter().collect();
+        let mut scope = parent.map(|_| "[ParentArea] = ?".to_string());
+        let mut levels: [TableData; 3] = Default::default();
+        for l in (1..=level).rev() {
+            let filter = scope.as_ref().map(|s| format!(" WHERE {s}")).unwrap_or_default();
+            let sql = format!("SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel{l}{filter}");
+            levels[3 - l] = query_table(conn, &sql, &params).await?;
+            scope = Some(format!("[ParentArea] IN (SELECT [ArealevelID] FROM Areaslevel{l}{filter})"));
         }
-    }).await;
-    match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        let filter = scope.map(|s| format!(" WHERE {s}")).unwrap_or_default();
+        let sql = format!("SELECT [ParentArea], [Area], [Ignore_Flag] FROM Room_Schedule{filter}");
+        let rooms = query_table(conn, &sql, &params).await?;
+        Ok(AreaLevelTables { levels, rooms })
+    }
+
+    fn rollup<'a>(&'a self, rules: &'a RollupRules) -> AreaRollup<'a> {
+        let [a3, a2, a1] = &self.levels;
+        AreaRollup::new([a3, a2, a1], &self.rooms, rules)
     }
 }
 
-async fn update_areaslevel3_cell(Path(db_name): Path<String>, Json(payload): Json<UpdateCell>) -> axum::response::Response {
-    let UpdateCell { parent_area, column, value } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let parent_area_cstr = match CString::new(parent_area) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid parent_area: {e}")),
-            };
-            let sql = format!("UPDATE Areaslevel3 SET [{}] = ? WHERE [ParentArea] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &parent_area_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
+// Rust side of the flag_set! SQL test
+fn flag_is_set(value: &str) -> bool {
+    !matches!(value.trim().to_ascii_uppercase().as_str(), "" | "0" | "N" | "NO" | "FALSE")
+}
+
+// Same matching rule as same_key, usable as a map key
+fn rollup_key(id: &str) -> String {
+    id.trim_end().to_ascii_lowercase()
+}
+
+fn room_area(rooms: &TableData, room: &[serde_json::Value]) -> f64 {
+    rooms.number(room, "Area")
+}
+
+// Room areas summed up Areaslevel1 > 2 > 3 under a project's RollupRules. The
+// area pages, snapshots and reports all take their totals from here.
+struct AreaRollup<'a> {
+    // Areaslevel3, Areaslevel2, Areaslevel1
+    levels: [&'a TableData; 3],
+    // Totals by rollup_key(ArealevelID), same order as `levels`
+    totals: [HashMap<String, AreaTotal>; 3],
+    rules: &'a RollupRules,
+}
+
+impl<'a> AreaRollup<'a> {
+    fn new(levels: [&'a TableData; 3], rooms: &TableData, rules: &'a RollupRules) -> AreaRollup<'a> {
+        let mut level1: HashMap<String, AreaTotal> = HashMap::new();
+        for room in rooms.rows.iter().filter(|r| !(rules.exclude_ignored && flag_is_set(&rooms.cell(r, "Ignore_Flag")))) {
+            level1.entry(rollup_key(&rooms.cell(room, "ParentArea"))).or_default().add(AreaTotal::room(room_area(rooms, room)));
         }
-    }).await;
+        let level1 = level1.into_iter().map(|(k, t)| (k, t.uplift(rules.uplift(1)))).collect();
+        let roll_up = |children: &TableData, totals: &HashMap<String, AreaTotal>, level: usize| {
+            let mut parents: HashMap<String, AreaTotal> = HashMap::new();
+            for child in &children.rows {
+                let total = totals.get(&rollup_key(&children.cell(child, "ArealevelID"))).copied().unwrap_or_default();
+                parents.entry(rollup_key(&children.cell(child, "ParentArea"))).or_default().add(total);
+            }
+            parents.into_iter().map(|(k, t)| (k, t.uplift(rules.uplift(level)))).collect()
+        };
+        let level2 = roll_up(levels[2], &level1, 2);
+        let level3 = roll_up(levels[1], &level2, 3);
+        AreaRollup { levels, totals: [level3, level2, level1], rules }
+    }
+
+    // `level` is 3, 2 or 1, as in the table names
+    fn total(&self, level: usize, id: &str) -> AreaTotal {
+        self.totals[3 - level].get(&rollup_key(id)).copied().unwrap_or_default()
+    }
+
+    // Rows as the Areaslevel pages show them, optionally under one parent
+    fn area_rows(&self, level: usize, parent: Option<&str>) -> TableData {
+        let t = self.levels[3 - level];
+        let rows = t.rows.iter()
+            .filter(|r| parent.is_none_or(|p| same_key(&t.cell(r, "ParentArea"), p)))
+            .map(|r| {
+                let total = self.total(level, &t.cell(r, "ArealevelID"));
+                let column = |name: &str| t.col(name).and_then(|i| r.get(i)).cloned().unwrap_or_default();
+                vec![
+                    column("ArealevelID"),
+                    column("ParentArea"),
+                    column("AreaDescription"),
+                    self.rules.value(total.area),
+                    self.rules.value(total.gross),
+                ]
+            })
+            .collect();
+        TableData { columns: ["ArealevelID", "ParentArea", "AreaDescription", "Area", "Gross_Area"].map(String::from).to_vec(), rows, ..Default::default() }
+    }
+}
+
+async fn get_areaslevel3(Path(db_name): Path<String>, Query(view): Query<SnapshotQuery>) -> axum::response::Response {
+    if let Some(name) = view.snapshot {
+        return snapshot_response(&db_name, &name, SnapshotView::Areaslevel3).await;
+    }
+    let rules = rollup_rules(&db_name).await;
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let tables = AreaLevelTables::load(conn, 3, None).await?;
+        Ok(tables.rollup(&rules).area_rows(3, None))
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&AREASLEVEL3_VERSION)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn update_areaslevel3_cell(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<UpdateCell>) -> axum::response::Response {
+    let UpdateCell { parent_area, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(parent_area.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Areaslevel3", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let value = cell_param(&value)?;
+        let column = table_column(conn, "Areaslevel3", &column).await?;
+        let sql = format!("UPDATE Areaslevel3 SET [{}] = ? WHERE [ParentArea] = ?", column);
+        versioned_update(conn, &AREASLEVEL3_VERSION, &parent_area, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(parent_area.as_str())]).await
+    })).await;
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    versioned_update_response(result)
 }
 
-async fn delete_areaslevel3_row(Path(db_name): Path<String>, Json(payload): Json<DeleteRow>) -> axum::response::Response {
+async fn delete_areaslevel3_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<DeleteRow>) -> axum::response::Response {
     let DeleteRow { parent_area } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let parent_area_cstr = match CString::new(parent_area) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid parent_area: {e}")),
-            };
-            let sql = "DELETE FROM Areaslevel3 WHERE [ParentArea] = ?";
-            let exec_result = conn.execute(sql, (&parent_area_cstr,));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
+    let change = ChangeEvent { parent_area: Some(parent_area.clone()), ..ChangeEvent::new("delete", "Areaslevel3", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let sql = "DELETE FROM Areaslevel3 WHERE [ParentArea] = ?";
+        if execute_counted(conn, sql, &[Some(parent_area.as_str())]).await? == 0 {
+            return Err(missing_row("Areaslevel3", "ParentArea", &parent_area));
         }
-    }).await;
+        Ok(())
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(()) => {
+            publish_change(&db_name, change);
+            axum::response::Response::new("OK".into())
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn add_areaslevel3_row(Path(db_name): Path<String>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let obj = payload.as_object().ok_or("Invalid row data")?;
-            let columns: Vec<_> = obj.keys().map(|k| format!("[{}]", k.replace('"', ""))).collect();
-            let placeholders: Vec<_> = (0..columns.len()).map(|_| "?").collect();
-            let values: Result<Vec<CString>, _> = obj.values().map(|v| CString::new(v.as_str().unwrap_or("")).map_err(|e| e.to_string())).collect();
-            let values = match values {
-                Ok(v) => v,
-                Err(e) => return Err(e),
-            };
-            let sql = format!("INSERT INTO Areaslevel3 ({}) VALUES ({})", columns.join(", "), placeholders.join(", "));
-            let exec_result = conn.execute(&sql, values.as_slice());
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
+async fn add_areaslevel3_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
+    let change = ChangeEvent { parent_area: payload_parent_area(&payload), ..ChangeEvent::new("add", "Areaslevel3", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let obj = payload.as_object().ok_or_else(|| AppError::validation("Invalid row data"))?;
+        let columns: Vec<String> = obj.keys().cloned().collect();
+        let values = obj.values().map(cell_param).collect::<Result<Vec<_>, _>>()?;
+        insert_returning(conn, "Areaslevel3", "ParentArea", &columns, &values).await
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(row) => {
+            publish_change(&db_name, ChangeEvent { row: Some(serde_json::Value::Object(row.clone())), ..change });
+            Json(row).into_response()
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn areaslevel2_page(Path((db_name, parent_id)): Path<(String, String)>) -> impl IntoResponse {
+async fn areaslevel2_page(Path((_db_name, _parent_id)): Path<(String, String)>) -> impl IntoResponse {
     Html(include_str!("../static/areaslevel2.html"))
 }
 
-async fn get_areaslevel2(Path((db_name, parent_id)): Path<(String, String)>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            use std::ffi::CString;
-            let parent_area_cstr = match CString::new(parent_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid parent_id: {e}")),
-            };
-            let query = r#"
-                SELECT a2.[ArealevelID], a2.[ParentArea], a2.[AreaDescription],
-                    FLOOR(ISNULL((
-                        SELECT SUM(CAST(r.[Area] AS FLOAT))
-                        FROM Room_Schedule r
-                        WHERE r.[ParentArea] IN (
-                            SELECT al1.[ArealevelID]
-                            FROM Areaslevel1 al1
-                            WHERE al1.[ParentArea] = a2.[ArealevelID]
-                        )
-                    ), 0)) AS [Area]
-                FROM Areaslevel2 a2
-                WHERE a2.[ParentArea] = ?
-            "#;
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, (&parent_area_cstr,)) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
+async fn get_areaslevel2(Path((db_name, parent_id)): Path<(String, String)>, Query(view): Query<SnapshotQuery>) -> axum::response::Response {
+    if let Some(name) = view.snapshot {
+        return snapshot_response(&db_name, &name, SnapshotView::Areaslevel2(&parent_id)).await;
+    }
+    let rules = rollup_rules(&db_name).await;
+    let result = with_connection(&db_name, {
+        let parent_id = parent_id.clone();
+        move |conn| Box::pin(async move {
+            let tables = AreaLevelTables::load(conn, 2, Some(&parent_id)).await?;
+            Ok(tables.rollup(&rules).area_rows(2, Some(&parent_id)))
+        })
     }).await;
     match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&AREASLEVEL2_VERSION)).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
-async fn update_areaslevel2_cell(Path(db_name): Path<String>, Json(payload): Json<UpdateAreaslevel2Cell>) -> axum::response::Response {
-    let UpdateAreaslevel2Cell { arealevel_id, column, value } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let arealevel_id_cstr = match CString::new(arealevel_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid arealevel_id: {e}")),
-            };
-            let sql = format!("UPDATE Areaslevel2 SET [{}] = ? WHERE [ArealevelID] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &arealevel_id_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
-    match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+async fn update_areaslevel2_cell(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<UpdateAreaslevel2Cell>) -> axum::response::Response {
+    let UpdateAreaslevel2Cell { arealevel_id, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(arealevel_id.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Areaslevel2", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let value = cell_param(&value)?;
+        let column = table_column(conn, "Areaslevel2", &column).await?;
+        let sql = format!("UPDATE Areaslevel2 SET [{}] = ? WHERE [ArealevelID] = ?", column);
+        versioned_update(conn, &AREASLEVEL2_VERSION, &arealevel_id, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(arealevel_id.as_str())]).await
+    })).await;
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    versioned_update_response(result)
 }
 
-async fn add_areaslevel2_row(Path(db_name): Path<String>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let obj = payload.as_object().ok_or("Invalid row data")?;
-            let columns: Vec<_> = obj.keys().map(|k| format!("[{}]", k.replace('"', ""))).collect();
-            let placeholders: Vec<_> = (0..columns.len()).map(|_| "?").collect();
-            let values: Result<Vec<CString>, _> = obj.values().map(|v| CString::new(v.as_str().unwrap_or("")).map_err(|e| e.to_string())).collect();
-            let values = match values {
-                Ok(v) => v,
-                Err(e) => return Err(e),
-            };
-            let sql = format!("INSERT INTO Areaslevel2 ({}) VALUES ({})", columns.join(", "), placeholders.join(", "));
-            let exec_result = conn.execute(&sql, values.as_slice());
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
+async fn add_areaslevel2_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
+    let change = ChangeEvent { parent_area: payload_parent_area(&payload), ..ChangeEvent::new("add", "Areaslevel2", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let obj = payload.as_object().ok_or_else(|| AppError::validation("Invalid row data"))?;
+        let columns: Vec<String> = obj.keys().cloned().collect();
+        let values = obj.values().map(cell_param).collect::<Result<Vec<_>, _>>()?;
+        insert_returning(conn, "Areaslevel2", "ArealevelID", &columns, &values).await
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(row) => {
+            publish_change(&db_name, ChangeEvent { row: Some(serde_json::Value::Object(row.clone())), ..change });
+            Json(row).into_response()
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn delete_areaslevel2_row(Path(db_name): Path<String>, Json(payload): Json<DeleteAreaslevel2Row>) -> axum::response::Response {
+async fn delete_areaslevel2_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<DeleteAreaslevel2Row>) -> axum::response::Response {
     let DeleteAreaslevel2Row { arealevel_id } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let arealevel_id_cstr = match CString::new(arealevel_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid arealevel_id: {e}")),
-            };
-            let sql = "DELETE FROM Areaslevel2 WHERE [ArealevelID] = ?";
-            let exec_result = conn.execute(sql, (&arealevel_id_cstr,));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
+    let change = ChangeEvent { key: Some(arealevel_id.clone()), ..ChangeEvent::new("delete", "Areaslevel2", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let sql = "DELETE FROM Areaslevel2 WHERE [ArealevelID] = ?";
+        if execute_counted(conn, sql, &[Some(arealevel_id.as_str())]).await? == 0 {
+            return Err(missing_row("Areaslevel2", "ArealevelID", &arealevel_id));
         }
-    }).await;
+        Ok(())
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(()) => {
+            publish_change(&db_name, change);
+            axum::response::Response::new("OK".into())
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn areaslevel1_page(Path((db_name, parent_id)): Path<(String, String)>) -> impl IntoResponse {
+async fn areaslevel1_page(Path((_db_name, _parent_id)): Path<(String, String)>) -> impl IntoResponse {
     Html(include_str!("../static/areaslevel1.html"))
 }
 
-async fn get_areaslevel1(Path((db_name, parent_id)): Path<(String, String)>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            use std::ffi::CString;
-            let parent_area_cstr = match CString::new(parent_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid parent_id: {e}")),
-            };
-            let query = r#"
-                SELECT a.[ArealevelID], a.[ParentArea], a.[AreaDescription],
-                    FLOOR(ISNULL(SUM(CAST(r.[Area] AS FLOAT)), 0)) AS [Area]
-                FROM Areaslevel1 a
-                LEFT JOIN Room_Schedule r ON r.[ParentArea] = a.[ArealevelID]
-                WHERE a.[ParentArea] = ?
-                GROUP BY a.[ArealevelID], a.[ParentArea], a.[AreaDescription]
-            "#;
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, (&parent_area_cstr,)) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
+async fn get_areaslevel1(Path((db_name, parent_id)): Path<(String, String)>, Query(view): Query<SnapshotQuery>) -> axum::response::Response {
+    if let Some(name) = view.snapshot {
+        return snapshot_response(&db_name, &name, SnapshotView::Areaslevel1(&parent_id)).await;
+    }
+    let rules = rollup_rules(&db_name).await;
+    let result = with_connection(&db_name, {
+        let parent_id = parent_id.clone();
+        move |conn| Box::pin(async move {
+            let tables = AreaLevelTables::load(conn, 1, Some(&parent_id)).await?;
+            Ok(tables.rollup(&rules).area_rows(1, Some(&parent_id)))
+        })
     }).await;
     match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&AREASLEVEL1_VERSION)).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
-async fn update_areaslevel1_cell(Path(db_name): Path<String>, Json(payload): Json<UpdateAreaslevel1Cell>) -> axum::response::Response {
-    let UpdateAreaslevel1Cell { arealevel_id, column, value } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let arealevel_id_cstr = match CString::new(arealevel_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid arealevel_id: {e}")),
-            };
-            let sql = format!("UPDATE Areaslevel1 SET [{}] = ? WHERE [ArealevelID] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &arealevel_id_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
-    match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+async fn update_areaslevel1_cell(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<UpdateAreaslevel1Cell>) -> axum::response::Response {
+    let UpdateAreaslevel1Cell { arealevel_id, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(arealevel_id.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Areaslevel1", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let value = cell_param(&value)?;
+        let column = table_column(conn, "Areaslevel1", &column).await?;
+        let sql = format!("UPDATE Areaslevel1 SET [{}] = ? WHERE [ArealevelID] = ?", column);
+        versioned_update(conn, &AREASLEVEL1_VERSION, &arealevel_id, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(arealevel_id.as_str())]).await
+    })).await;
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    versioned_update_response(result)
 }
 
-async fn add_areaslevel1_row(Path(db_name): Path<String>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let obj = payload.as_object().ok_or("Invalid row data")?;
-            let columns: Vec<_> = obj.keys().map(|k| format!("[{}]", k.replace('"', ""))).collect();
-            let placeholders: Vec<_> = (0..columns.len()).map(|_| "?").collect();
-            let values: Result<Vec<CString>, _> = obj.values().map(|v| CString::new(v.as_str().unwrap_or("")).map_err(|e| e.to_string())).collect();
-            let values = match values {
-                Ok(v) => v,
-                Err(e) => return Err(e),
-            };
-            let sql = format!("INSERT INTO Areaslevel1 ({}) VALUES ({})", columns.join(", "), placeholders.join(", "));
-            let exec_result = conn.execute(&sql, values.as_slice());
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
+async fn add_areaslevel1_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
+    let change = ChangeEvent { parent_area: payload_parent_area(&payload), ..ChangeEvent::new("add", "Areaslevel1", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let obj = payload.as_object().ok_or_else(|| AppError::validation("Invalid row data"))?;
+        let columns: Vec<String> = obj.keys().cloned().collect();
+        let values = obj.values().map(cell_param).collect::<Result<Vec<_>, _>>()?;
+        insert_returning(conn, "Areaslevel1", "ArealevelID", &columns, &values).await
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(row) => {
+            publish_change(&db_name, ChangeEvent { row: Some(serde_json::Value::Object(row.clone())), ..change });
+            Json(row).into_response()
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn delete_areaslevel1_row(Path(db_name): Path<String>, Json(payload): Json<DeleteAreaslevel1Row>) -> axum::response::Response {
+async fn delete_areaslevel1_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<DeleteAreaslevel1Row>) -> axum::response::Response {
     let DeleteAreaslevel1Row { arealevel_id } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let arealevel_id_cstr = match CString::new(arealevel_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid arealevel_id: {e}")),
-            };
-            let sql = "DELETE FROM Areaslevel1 WHERE [ArealevelID] = ?";
-            let exec_result = conn.execute(sql, (&arealevel_id_cstr,));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
+    let change = ChangeEvent { key: Some(arealevel_id.clone()), ..ChangeEvent::new("delete", "Areaslevel1", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let sql = "DELETE FROM Areaslevel1 WHERE [ArealevelID] = ?";
+        if execute_counted(conn, sql, &[Some(arealevel_id.as_str())]).await? == 0 {
+            return Err(missing_row("Areaslevel1", "ArealevelID", &arealevel_id));
         }
-    }).await;
+        Ok(())
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(()) => {
+            publish_change(&db_name, change);
+            axum::response::Response::new("OK".into())
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn room_schedule_page(Path((db_name, parent_id)): Path<(String, String)>) -> impl IntoResponse {
+async fn room_schedule_page(Path((_db_name, _parent_id)): Path<(String, String)>) -> impl IntoResponse {
     Html(include_str!("../static/room_schedule.html"))
 }
 
-async fn get_room_schedule(Path((db_name, parent_id)): Path<(String, String)>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            use std::ffi::CString;
-            let parent_area_cstr = match CString::new(parent_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid parent_id: {e}")),
-            };
-            let query = "SELECT [ParentArea], [Room_Id], [Project_Room_Description], [Ignore_Flag], [Internal_Notes], [Room_Code], [Area] FROM Room_Schedule WHERE [ParentArea] = ?";
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, (&parent_area_cstr,)) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match odbc_api::buffers::TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
-    }).await;
+// Room_Schedule columns as the room pages and room data sheets show them
+const ROOM_SCHEDULE_SELECT: &str = "SELECT [ParentArea], [Room_Id], [Project_Room_Description], [Ignore_Flag], [Internal_Notes], [Room_Code], [Area] FROM Room_Schedule";
+
+async fn get_room_schedule(Path((db_name, parent_id)): Path<(String, String)>, Query(view): Query<SnapshotQuery>) -> axum::response::Response {
+    if let Some(name) = view.snapshot {
+        return snapshot_response(&db_name, &name, SnapshotView::RoomSchedule(Some(&parent_id))).await;
+    }
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let query = &format!("{} WHERE [ParentArea] = ?", ROOM_SCHEDULE_SELECT);
+        query_table(conn, query, &[Some(parent_id.as_str())]).await
+    })).await;
     match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&ROOM_SCHEDULE_VERSION)).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
-async fn update_room_schedule_cell(Path(db_name): Path<String>, Json(payload): Json<UpdateRoomScheduleCell>) -> axum::response::Response {
-    let UpdateRoomScheduleCell { room_id, column, value } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let room_id_cstr = match CString::new(room_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid room_id: {e}")),
-            };
-            let sql = format!("UPDATE Room_Schedule SET [{}] = ? WHERE [Room_Id] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &room_id_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
-    match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+async fn update_room_schedule_cell(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<UpdateRoomScheduleCell>) -> axum::response::Response {
+    let UpdateRoomScheduleCell { room_id, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(room_id.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Room_Schedule", &user) };
+    let warning = match reference_warning(&db_name, "Room_Schedule", &column, &cell_text(&value)).await {
+        Ok(w) => w,
+        Err(e) => return e.into_response(),
+    };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let value = cell_param(&value)?;
+        let column = table_column(conn, "Room_Schedule", &column).await?;
+        let sql = format!("UPDATE Room_Schedule SET [{}] = ? WHERE [Room_Id] = ?", column);
+        versioned_update(conn, &ROOM_SCHEDULE_VERSION, &room_id, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(room_id.as_str())]).await
+    })).await;
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    with_reference_warning(versioned_update_response(result), warning)
 }
 
-async fn add_room_schedule_row(Path(db_name): Path<String>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let obj = payload.as_object().ok_or("Invalid row data")?;
-            let columns: Vec<_> = obj.keys().map(|k| format!("[{}]", k.replace('"', ""))).collect();
-            let placeholders: Vec<_> = (0..columns.len()).map(|_| "?").collect();
-            let values: Result<Vec<CString>, _> = obj.values().map(|v| CString::new(v.as_str().unwrap_or("")).map_err(|e| e.to_string())).collect();
-            let values = match values {
-                Ok(v) => v,
-                Err(e) => return Err(e),
-            };
-            let sql = format!("INSERT INTO Room_Schedule ({}) VALUES ({})", columns.join(", "), placeholders.join(", "));
-            let exec_result = conn.execute(&sql, values.as_slice());
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
-    }).await;
+async fn add_room_schedule_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
+    let change = ChangeEvent { parent_area: payload_parent_area(&payload), ..ChangeEvent::new("add", "Room_Schedule", &user) };
+    let warning = match row_reference_warning(&db_name, "Room_Schedule", &payload).await {
+        Ok(w) => w,
+        Err(e) => return e.into_response(),
+    };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let obj = payload.as_object().ok_or_else(|| AppError::validation("Invalid row data"))?;
+        let columns: Vec<String> = obj.keys().cloned().collect();
+        let values = obj.values().map(cell_param).collect::<Result<Vec<_>, _>>()?;
+        insert_returning(conn, "Room_Schedule", "Room_Id", &columns, &values).await
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(row) => {
+            publish_change(&db_name, ChangeEvent { row: Some(serde_json::Value::Object(row.clone())), ..change });
+            with_reference_warning(Json(row).into_response(), warning)
+        }
+        Err(e) => e.into_response(),
     }
 }
 
-async fn delete_room_schedule_row(Path(db_name): Path<String>, Json(payload): Json<DeleteRoomScheduleRow>) -> axum::response::Response {
+async fn delete_room_schedule_row(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<DeleteRoomScheduleRow>) -> axum::response::Response {
     let DeleteRoomScheduleRow { room_id } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let room_id_cstr = match CString::new(room_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid room_id: {e}")),
-            };
-            let sql = "DELETE FROM Room_Schedule WHERE [Room_Id] = ?";
-            let exec_result = conn.execute(sql, (&room_id_cstr,));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
+    let change = ChangeEvent { key: Some(room_id.clone()), ..ChangeEvent::new("delete", "Room_Schedule", &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let sql = "DELETE FROM Room_Schedule WHERE [Room_Id] = ?";
+        if execute_counted(conn, sql, &[Some(room_id.as_str())]).await? == 0 {
+            return Err(missing_row("Room_Schedule", "Room_Id", &room_id));
         }
-    }).await;
+        Ok(())
+    })).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(()) => {
+            publish_change(&db_name, change);
+            axum::response::Response::new("OK".into())
+        }
+        Err(e) => e.into_response(),
     }
 }
 
 // Handler for the new Room_Schedule All page
-async fn room_schedule_all_page(Path(db_name): Path<String>) -> impl IntoResponse {
+async fn room_schedule_all_page(Path(_db_name): Path<String>) -> impl IntoResponse {
     Html(include_str!("../static/room_schedule_all.html"))
 }
 
 // Handler to get all Room_Schedule rows for a db (no parent filter)
-async fn get_room_schedule_all(Path(db_name): Path<String>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let query = "SELECT [ParentArea], [Room_Id], [Project_Room_Description], [Ignore_Flag], [Internal_Notes], [Room_Code], [Area] FROM Room_Schedule";
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, ()) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match odbc_api::buffers::TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
-    }).await;
+async fn get_room_schedule_all(Path(db_name): Path<String>, Query(view): Query<SnapshotQuery>) -> axum::response::Response {
+    if let Some(name) = view.snapshot {
+        return snapshot_response(&db_name, &name, SnapshotView::RoomSchedule(None)).await;
+    }
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let query = ROOM_SCHEDULE_SELECT;
+        query_table(conn, query, &[]).await
+    })).await;
     match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&ROOM_SCHEDULE_VERSION)).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
 // Handler for the Item_Schedule page
-async fn item_schedule_page(Path((db_name, room_code)): Path<(String, String)>) -> impl IntoResponse {
+async fn item_schedule_page(Path((_db_name, _room_code)): Path<(String, String)>) -> impl IntoResponse {
     Html(include_str!("../static/item_schedule.html"))
 }
 
 // Handler to get Item_Schedule rows filtered by Room_Code
-async fn get_item_schedule(Path((db_name, room_code)): Path<(String, String)>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
+async fn get_item_schedule(Path((db_name, room_code)): Path<(String, String)>, Query(view): Query<SnapshotQuery>) -> axum::response::Response {
+    if let Some(name) = view.snapshot {
+        return snapshot_response(&db_name, &name, SnapshotView::ItemSchedule(&room_code)).await;
+    }
+    let result = with_connection(&db_name, {
         let room_code = room_code.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let query = "SELECT s.[Item_schedule_id], s.[Item_Ref], d.[Item_Description], s.[Room_Code], s.[Ignore_flag], s.[Qty_New], s.[Qty_Trans], s.[Notes] FROM Item_Schedule s LEFT JOIN Item_descriptions d ON s.[Item_Ref] = d.[ADB_Ref] WHERE s.[Room_Code] = ?";
-            let room_code_cstr = match CString::new(room_code) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid room_code: {e}")),
-            };
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, (&room_code_cstr,)) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match odbc_api::buffers::TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
+        move |conn| Box::pin(async move {
+            let query = "SELECT s.[Item_schedule_id], s.[Item_Ref], d.[Item_Description], s.[Room_Code], s.[Ignore_flag], s.[Qty_New], s.[Qty_Trans], s.[Notes] FROM Item_Schedule s LEFT JOIN Item_descriptions d ON s.[Item_Ref] = d.[ADB_Ref] WHERE s.[Room_Code] = ? AND ISNULL(s.[instance_variant], 0) = 0";
+            query_table(conn, query, &[Some(room_code.as_str())]).await
+        })
     }).await;
     match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&ITEM_SCHEDULE_VERSION)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Item_Schedule rows with instance_variant 0 are the room type's list, shared by
+// every room with that Room_Code. A row whose instance_variant is a Room_Id belongs
+// to that room only: it replaces the type row with the same Item_Ref, adds an item
+// the type doesn't have, or (with both quantities 0) removes the item from the room.
+#[derive(Deserialize)]
+struct RoomItemsQuery {
+    #[serde(default)]
+    include_removed: bool,
+}
+
+#[derive(Serialize)]
+struct RoomItems {
+    room_id: String,
+    room_code: String,
+    #[serde(flatten)]
+    items: TableData,
+}
+
+// Per-room rows live in instance_variant, an integer column, so only a room whose
+// Room_Id is a non-zero number can have its own; other rooms show their type's list
+fn room_variant(room_id: &str) -> Option<i64> {
+    room_id.trim().parse::<i64>().ok().filter(|variant| *variant != 0)
+}
+
+// Room_Code of a room, or NotFound
+async fn room_code_of(conn: &mut DbConn, room_id: &str) -> Result<String, AppError> {
+    let room = query_table(conn, "SELECT [Room_Code] FROM Room_Schedule WHERE [Room_Id] = ?", &[Some(room_id)]).await?;
+    match room.rows.first() {
+        Some(row) => Ok(row.first().map(|c| cell_text(c).into_owned()).unwrap_or_default()),
+        None => Err(missing_row("Room_Schedule", "Room_Id", room_id)),
+    }
+}
+
+// A per-room row must name an existing room and carry that room's Room_Code
+async fn check_room_instance(conn: &mut DbConn, variant: i64, room_code: &str) -> Result<(), AppError> {
+    let room_id = variant.to_string();
+    let actual = room_code_of(conn, &room_id).await?;
+    if !same_key(&actual, room_code) {
+        return Err(AppError::validation(format!("Room {} has Room_Code {}, not {}", room_id, actual, room_code))
+            .with_details(serde_json::json!({ "room_id": room_id, "room_code": actual })));
+    }
+    Ok(())
+}
+
+// Columns that tie an Item_Schedule row to a room
+fn is_room_instance_column(column: &str) -> bool {
+    column.eq_ignore_ascii_case("Room_Code") || column.eq_ignore_ascii_case("instance_variant")
+}
+
+// Check a per-room Item_Schedule row still names a room with its Room_Code, with
+// `edited` (column, new value) applied over what the row holds now
+async fn check_item_room_instance(conn: &mut DbConn, item_schedule_id: &str, edited: Option<(&str, &str)>) -> Result<(), AppError> {
+    let row = query_table(conn, "SELECT [Room_Code], [instance_variant] FROM Item_Schedule WHERE [Item_schedule_id] = ?", &[Some(item_schedule_id)]).await?;
+    // A missing row is reported by the update itself
+    let Some(r) = row.rows.first() else {
+        return Ok(());
+    };
+    let (mut room_code, mut variant) = (row.cell(r, "Room_Code").into_owned(), row.cell(r, "instance_variant").into_owned());
+    match edited {
+        Some((column, value)) if column.eq_ignore_ascii_case("Room_Code") => room_code = value.to_string(),
+        Some((column, value)) if column.eq_ignore_ascii_case("instance_variant") => variant = value.to_string(),
+        _ => {}
+    }
+    let variant = match variant.trim() {
+        "" => 0,
+        v => v.parse::<i64>().map_err(|_| AppError::validation(format!("instance_variant {} is not a whole number", v)))?,
+    };
+    if variant == 0 {
+        return Ok(());
+    }
+    check_room_instance(conn, variant, &room_code).await
+}
+
+// Page for one room's effective item list
+async fn room_items_page(Path((_db_name, _room_id)): Path<(String, String)>) -> impl IntoResponse {
+    Html(include_str!("../static/room_items.html"))
+}
+
+// SELECT and parameters for one room's effective Item_Schedule
+fn room_items_query(room_id: &str, room_code: &str, include_removed: bool) -> (String, Vec<String>) {
+    let removed = "ISNULL(s.[Qty_New], 0) = 0 AND ISNULL(s.[Qty_Trans], 0) = 0";
+    let select = format!(
+        "SELECT s.[Item_schedule_id], s.[Item_Ref], d.[Item_Description], m.[Cat], s.[Room_Code], s.[Ignore_flag], s.[Qty_New], s.[Qty_Trans], s.[Notes], s.[instance_variant], \
+         CASE WHEN ISNULL(s.[instance_variant], 0) = 0 THEN 'room_type' WHEN {removed} THEN 'removed' ELSE 'room' END AS [Source] \
+         FROM Item_Schedule s LEFT JOIN Item_descriptions d ON s.[Item_Ref] = d.[ADB_Ref] LEFT JOIN ERM m ON s.[Item_Ref] = m.[ADB_Code]"
+    );
+    let Some(variant) = room_variant(room_id) else {
+        let sql = format!("{} WHERE s.[Room_Code] = ? AND ISNULL(s.[instance_variant], 0) = 0 ORDER BY s.[Item_Ref]", select);
+        return (sql, vec![room_code.to_string()]);
+    };
+    let sql = format!(
+        "{} WHERE s.[Room_Code] = ? AND ( \
+            (ISNULL(s.[instance_variant], 0) = 0 AND NOT EXISTS (SELECT 1 FROM Item_Schedule o WHERE o.[Room_Code] = s.[Room_Code] AND o.[instance_variant] = ? AND o.[Item_Ref] = s.[Item_Ref])) \
+            OR (s.[instance_variant] = ?{}) \
+         ) ORDER BY s.[Item_Ref]",
+        select,
+        if include_removed { String::new() } else { format!(" AND NOT ({})", removed) },
+    );
+    let variant = variant.to_string();
+    (sql, vec![room_code.to_string(), variant.clone(), variant])
+}
+
+// Effective Item_Schedule for one room: its type's list with the room's own rows applied
+async fn room_items(conn: &mut DbConn, room_id: &str, room_code: &str, include_removed: bool) -> Result<RoomItems, AppError> {
+    let (sql, params) = room_items_query(room_id, room_code, include_removed);
+    let params: Vec<Option<&str>> = params.iter().map(|p| Some(p.as_str())).collect();
+    let items = query_table(conn, &sql, &params).await?;
+    Ok(RoomItems { room_id: room_id.to_string(), room_code: room_code.to_string(), items: items.with_versions(&ITEM_SCHEDULE_VERSION) })
+}
+
+// Handler to get the effective Item_Schedule for one room
+async fn get_room_items(Path((db_name, room_id)): Path<(String, String)>, Query(query): Query<RoomItemsQuery>) -> axum::response::Response {
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let room_code = room_code_of(conn, &room_id).await?;
+        room_items(conn, &room_id, &room_code, query.include_removed).await
+    })).await;
+    match result {
+        Ok(items) => Json(items).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
 // Handler to update a cell in Item_Schedule
-async fn update_item_schedule_cell(Path(db_name): Path<String>, Json(payload): Json<UpdateItemScheduleCell>) -> axum::response::Response {
-    let UpdateItemScheduleCell { item_schedule_id, column, value } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
+async fn update_item_schedule_cell(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<UpdateItemScheduleCell>) -> axum::response::Response {
+    let UpdateItemScheduleCell { item_schedule_id, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(item_schedule_id.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Item_Schedule", &user) };
+    let warning = match reference_warning(&db_name, "Item_Schedule", &column, &cell_text(&value)).await {
+        Ok(w) => w,
+        Err(e) => return e.into_response(),
+    };
+    let result = with_connection(&db_name, {
         let item_schedule_id = item_schedule_id.clone();
         let column = column.clone();
         let value = value.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let item_schedule_id_cstr = match CString::new(item_schedule_id) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid item_schedule_id: {e}")),
-            };
-            let sql = format!("UPDATE Item_Schedule SET [{}] = ? WHERE [Item_schedule_id] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &item_schedule_id_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
+        move |conn| Box::pin(async move {
+            set_session_user(conn, &user).await?;
+            let value = cell_param(&value)?;
+            let column = table_column(conn, "Item_Schedule", &column).await?;
+            if is_room_instance_column(&column) {
+                check_item_room_instance(conn, &item_schedule_id, Some((&column, value.as_deref().unwrap_or_default()))).await?;
             }
-        }
+            let sql = format!("UPDATE Item_Schedule SET [{}] = ? WHERE [Item_schedule_id] = ?", column);
+            versioned_update(conn, &ITEM_SCHEDULE_VERSION, &item_schedule_id, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(item_schedule_id.as_str())]).await
+        })
     }).await;
-    match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    with_reference_warning(versioned_update_response(result), warning)
 }
 
 // Handler for the Item_Descriptions search page
-async fn item_descriptions_search_page(Path(db_name): Path<String>) -> impl IntoResponse {
+async fn item_descriptions_search_page(Path(_db_name): Path<String>) -> impl IntoResponse {
     Html(include_str!("../static/item_descriptions_search.html"))
 }
 
 // Handler to get all Item_descriptions rows for a db
 async fn get_item_descriptions(Path(db_name): Path<String>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let query = "SELECT d.[ADB_Ref], d.[Item_Description], d.[Unit_Cost], e.[Cat], e.[Group] FROM Item_descriptions d LEFT JOIN ERM e ON d.[ADB_Ref] = e.[ADB_Code]";
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, ()) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match odbc_api::buffers::TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
-    }).await;
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let query = "SELECT d.[ADB_Ref], d.[Item_Description], d.[Unit_Cost], e.[Cat], e.[Group] FROM Item_descriptions d LEFT JOIN ERM e ON d.[ADB_Ref] = e.[ADB_Code]";
+        query_table(conn, query, &[]).await
+    })).await;
     match result {
-        Ok(Ok(table)) => Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => Json(table.with_versions(&ITEM_DESCRIPTION_VERSION)).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
 // Handler for the Room_Types search page
-async fn room_types_search_page(axum::extract::Path(db_name): axum::extract::Path<String>) -> impl axum::response::IntoResponse {
+async fn room_types_search_page(axum::extract::Path(_db_name): axum::extract::Path<String>) -> impl axum::response::IntoResponse {
     axum::response::Html(include_str!("../static/room_types_search.html"))
 }
 
 // Handler to get all Room_Types rows for a db
 async fn get_room_types(axum::extract::Path(db_name): axum::extract::Path<String>) -> axum::response::Response {
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let query = "SELECT [Room_Code], [Room_Description], [Area] FROM Room_Types";
-            let mut columns = Vec::new();
-            let mut rows = Vec::new();
-            let cursor = match conn.execute(query, ()) {
-                Ok(cursor) => cursor,
-                Err(e) => return Err(e.to_string()),
-            };
-            if let Some(mut cursor) = cursor {
-                columns = match cursor.column_names() {
-                    Ok(names) => names.map(|n| n.unwrap_or_default()).collect(),
-                    Err(_) => vec![],
-                };
-                let batch_size = 32;
-                let mut buffers = match odbc_api::buffers::TextRowSet::for_cursor(batch_size, &mut cursor, Some(4096)) {
-                    Ok(buffers) => buffers,
-                    Err(e) => return Err(e.to_string()),
-                };
-                let mut row_set_cursor = match cursor.bind_buffer(&mut buffers) {
-                    Ok(rsc) => rsc,
-                    Err(e) => return Err(e.to_string()),
-                };
-                while let Some(batch) = match row_set_cursor.fetch() {
-                    Ok(batch) => batch,
-                    Err(e) => return Err(e.to_string()),
-                } {
-                    for row_idx in 0..batch.num_rows() {
-                        let mut row = Vec::new();
-                        for col_idx in 0..batch.num_cols() {
-                            let cell = batch.at(col_idx, row_idx)
-                                .and_then(|bytes| std::str::from_utf8(bytes).ok())
-                                .unwrap_or("").to_string();
-                            row.push(cell);
-                        }
-                        rows.push(row);
-                    }
-                }
-            }
-            Ok(TableData { columns, rows })
-        }
-    }).await;
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let query = "SELECT [Room_Code], [Room_Description], [Area] FROM Room_Types";
+        query_table(conn, query, &[]).await
+    })).await;
     match result {
-        Ok(Ok(table)) => axum::Json(table).into_response(),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(table) => axum::Json(table.with_versions(&ROOM_TYPE_VERSION)).into_response(),
+        Err(e) => e.into_response(),
     }
 }
 
@@ -1257,43 +1246,30 @@ async fn get_room_types(axum::extract::Path(db_name): axum::extract::Path<String
 struct UpdateRoomTypeCell {
     room_code: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
-async fn update_room_type_cell(axum::extract::Path(db_name): axum::extract::Path<String>, axum::Json(payload): axum::Json<UpdateRoomTypeCell>) -> axum::response::Response {
-    let UpdateRoomTypeCell { room_code, column, value } = payload;
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
+async fn update_room_type_cell(axum::extract::Path(db_name): axum::extract::Path<String>, Extension(user): Extension<CurrentUser>, axum::Json(payload): axum::Json<UpdateRoomTypeCell>) -> axum::response::Response {
+    let UpdateRoomTypeCell { room_code, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(room_code.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Room_Types", &user) };
+    let result = with_connection(&db_name, {
         let room_code = room_code.clone();
         let column = column.clone();
         let value = value.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match std::ffi::CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let room_code_cstr = match std::ffi::CString::new(room_code) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid room_code: {e}")),
-            };
-            let sql = format!("UPDATE Room_Types SET [{}] = ? WHERE [Room_Code] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &room_code_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
+        move |conn| Box::pin(async move {
+            set_session_user(conn, &user).await?;
+            let value = cell_param(&value)?;
+            let column = table_column(conn, "Room_Types", &column).await?;
+            let sql = format!("UPDATE Room_Types SET [{}] = ? WHERE [Room_Code] = ?", column);
+            versioned_update(conn, &ROOM_TYPE_VERSION, &room_code, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(room_code.as_str())]).await
+        })
     }).await;
-    match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    versioned_update_response(result)
 }
 
 // Handler to update a cell in Item_descriptions
@@ -1301,47 +1277,34 @@ async fn update_room_type_cell(axum::extract::Path(db_name): axum::extract::Path
 struct UpdateItemDescriptionCell {
     adb_ref: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
-async fn update_item_description_cell(axum::extract::Path(db_name): axum::extract::Path<String>, axum::Json(payload): axum::Json<UpdateItemDescriptionCell>) -> axum::response::Response {
-    let UpdateItemDescriptionCell { adb_ref, column, value } = payload;
+async fn update_item_description_cell(axum::extract::Path(db_name): axum::extract::Path<String>, Extension(user): Extension<CurrentUser>, axum::Json(payload): axum::Json<UpdateItemDescriptionCell>) -> axum::response::Response {
+    let UpdateItemDescriptionCell { adb_ref, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(adb_ref.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "Item_descriptions", &user) };
     // Only allow updates to Item_Description and Unit_Cost
     if column != "Item_Description" && column != "Unit_Cost" {
-        return (axum::http::StatusCode::BAD_REQUEST, "Only Item_Description and Unit_Cost can be updated").into_response();
+        return AppError::validation("Only Item_Description and Unit_Cost can be updated").into_response();
     }
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
+    let result = with_connection(&db_name, {
         let adb_ref = adb_ref.clone();
         let column = column.clone();
         let value = value.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match std::ffi::CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let adb_ref_cstr = match std::ffi::CString::new(adb_ref) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid adb_ref: {e}")),
-            };
-            let sql = format!("UPDATE Item_descriptions SET [{}] = ? WHERE [ADB_Ref] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &adb_ref_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
-            }
-        }
+        move |conn| Box::pin(async move {
+            set_session_user(conn, &user).await?;
+            let value = cell_param(&value)?;
+            let column = table_column(conn, "Item_descriptions", &column).await?;
+            let sql = format!("UPDATE Item_descriptions SET [{}] = ? WHERE [ADB_Ref] = ?", column);
+            versioned_update(conn, &ITEM_DESCRIPTION_VERSION, &adb_ref, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(adb_ref.as_str())]).await
+        })
     }).await;
-    match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
     }
+    versioned_update_response(result)
 }
 
 // Handler to update Cat or Group in ERM
@@ -1349,92 +1312,1684 @@ async fn update_item_description_cell(axum::extract::Path(db_name): axum::extrac
 struct UpdateErmCell {
     adb_ref: String,
     column: String,
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
 }
 
-async fn update_erm_cell(axum::extract::Path(db_name): axum::extract::Path<String>, axum::Json(payload): axum::Json<UpdateErmCell>) -> axum::response::Response {
-    let UpdateErmCell { adb_ref, column, value } = payload;
+async fn update_erm_cell(axum::extract::Path(db_name): axum::extract::Path<String>, Extension(user): Extension<CurrentUser>, axum::Json(payload): axum::Json<UpdateErmCell>) -> axum::response::Response {
+    let UpdateErmCell { adb_ref, column, value, expected_version } = payload;
+    let change = ChangeEvent { key: Some(adb_ref.clone()), column: Some(column.clone()), value: Some(value.clone()), ..ChangeEvent::new("update", "ERM", &user) };
     // Only allow updates to Cat and Group
     if column != "Cat" && column != "Group" {
-        return (axum::http::StatusCode::BAD_REQUEST, "Only Cat and Group can be updated").into_response();
+        return AppError::validation("Only Cat and Group can be updated").into_response();
     }
-    let pool = get_or_create_pool(&db_name).await;
-    let result = tokio::task::spawn_blocking({
-        let pool = pool.clone();
+    let result = with_connection(&db_name, {
         let adb_ref = adb_ref.clone();
         let column = column.clone();
         let value = value.clone();
-        move || {
-            let mut conn = match tokio::runtime::Handle::current().block_on(pool.get()) {
-                Ok(conn) => conn,
-                Err(e) => return Err(format!("{:?}", e)),
-            };
-            let value_cstr = match std::ffi::CString::new(value) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid value: {e}")),
-            };
-            let adb_ref_cstr = match std::ffi::CString::new(adb_ref) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Invalid adb_ref: {e}")),
+        move |conn| Box::pin(async move {
+            set_session_user(conn, &user).await?;
+            let value = cell_param(&value)?;
+            let column = table_column(conn, "ERM", &column).await?;
+            let sql = format!("UPDATE ERM SET [{}] = ? WHERE [ADB_Code] = ?", column);
+            versioned_update(conn, &ERM_VERSION, &adb_ref, &column, expected_version.as_deref(), &sql, &[value.as_deref(), Some(adb_ref.as_str())]).await
+        })
+    }).await;
+    if let Ok(VersionedUpdate::Updated(version)) = &result {
+        publish_change(&db_name, ChangeEvent { version: version.clone(), ..change });
+    }
+    versioned_update_response(result)
+}
+
+// One message on a database's change feed
+#[derive(Serialize, Clone, Debug)]
+struct ChangeEvent {
+    // "add", "update", "delete", "editing", or "resync" when open pages should reload
+    // the table instead of patching it: after integrity fixes and rollup rule changes,
+    // and for a subscriber that fell too far behind
+    kind: String,
+    table: String,
+    user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<String>,
+    // JSON null when the cell was set to NULL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_area: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    editing: Option<bool>,
+}
+
+impl ChangeEvent {
+    fn new(kind: &str, table: &str, user: &CurrentUser) -> ChangeEvent {
+        ChangeEvent {
+            kind: kind.to_string(),
+            table: table.to_string(),
+            user: user.username.clone(),
+            key: None,
+            column: None,
+            value: None,
+            version: None,
+            parent_area: None,
+            room_code: None,
+            row: None,
+            editing: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EditingNotice {
+    table: String,
+    key: String,
+    column: String,
+    editing: bool,
+}
+
+// Per-database broadcast channels, keyed by lower-cased database name
+static CHANGE_FEEDS: Lazy<DashMap<String, tokio::sync::broadcast::Sender<ChangeEvent>>> = Lazy::new(DashMap::new);
+
+fn publish_change(db_name: &str, event: ChangeEvent) {
+    if event.kind != "editing" {
+        *METRICS.mutations.entry((event.table.clone(), event.kind.clone())).or_default() += 1;
+    }
+    let key = db_name.to_lowercase();
+    let unheard = CHANGE_FEEDS.get(&key).is_some_and(|feed| feed.send(event).is_err());
+    // No receivers just means nobody has the database open any more
+    if unheard {
+        CHANGE_FEEDS.remove_if(&key, |_, feed| feed.receiver_count() == 0);
+    }
+}
+
+// Forget the feeds of databases whose pages have all been closed
+fn drop_idle_feeds() {
+    CHANGE_FEEDS.retain(|_, feed| feed.receiver_count() > 0);
+}
+
+fn payload_parent_area(payload: &serde_json::Value) -> Option<String> {
+    payload.get("ParentArea").and_then(|v| v.as_str()).map(|s| s.to_string())
+}
+
+// Server-Sent Events stream of every change made to one database
+async fn change_feed(Path(db_name): Path<String>) -> axum::response::sse::Sse<impl tokio_stream::Stream<Item = Result<axum::response::sse::Event, std::convert::Infallible>>> {
+    use tokio_stream::StreamExt;
+    let receiver = CHANGE_FEEDS
+        .entry(db_name.to_lowercase())
+        .or_insert_with(|| tokio::sync::broadcast::channel(256).0)
+        .subscribe();
+    let stream = tokio_stream::wrappers::BroadcastStream::new(receiver).map(|message| {
+        let event = axum::response::sse::Event::default();
+        Ok(match message {
+            Ok(change) => event.json_data(&change).unwrap_or_default(),
+            // The page fell too far behind to patch itself; ask it to reload
+            Err(_) => event.data(r#"{"kind":"resync"}"#),
+        })
+    });
+    axum::response::sse::Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default())
+}
+
+async fn announce_editing(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<EditingNotice>) -> axum::response::Response {
+    let EditingNotice { table, key, column, editing } = payload;
+    publish_change(&db_name, ChangeEvent {
+        key: Some(key),
+        column: Some(column),
+        editing: Some(editing),
+        ..ChangeEvent::new("editing", &table, &user)
+    });
+    axum::response::Response::new("OK".into())
+}
+
+// Columns hashed into a row's version token, and the locking query that re-reads them by key
+struct RowVersionSpec {
+    table: &'static str,
+    key_column: &'static str,
+    columns: &'static [&'static str],
+    select: &'static str,
+}
+
+const AREASLEVEL3_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Areaslevel3",
+    key_column: "ParentArea",
+    columns: &["ArealevelID", "ParentArea", "AreaDescription"],
+    select: "SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel3 WITH (UPDLOCK, HOLDLOCK) WHERE [ParentArea] = ?",
+};
+const AREASLEVEL2_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Areaslevel2",
+    key_column: "ArealevelID",
+    columns: &["ArealevelID", "ParentArea", "AreaDescription"],
+    select: "SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel2 WITH (UPDLOCK, HOLDLOCK) WHERE [ArealevelID] = ?",
+};
+const AREASLEVEL1_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Areaslevel1",
+    key_column: "ArealevelID",
+    columns: &["ArealevelID", "ParentArea", "AreaDescription"],
+    select: "SELECT [ArealevelID], [ParentArea], [AreaDescription] FROM Areaslevel1 WITH (UPDLOCK, HOLDLOCK) WHERE [ArealevelID] = ?",
+};
+const ROOM_SCHEDULE_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Room_Schedule",
+    key_column: "Room_Id",
+    columns: &["ParentArea", "Room_Id", "Project_Room_Description", "Ignore_Flag", "Internal_Notes", "Room_Code", "Area"],
+    select: "SELECT [ParentArea], [Room_Id], [Project_Room_Description], [Ignore_Flag], [Internal_Notes], [Room_Code], [Area] FROM Room_Schedule WITH (UPDLOCK, HOLDLOCK) WHERE [Room_Id] = ?",
+};
+const ITEM_SCHEDULE_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Item_Schedule",
+    key_column: "Item_schedule_id",
+    columns: &["Item_schedule_id", "Item_Ref", "Room_Code", "Ignore_flag", "Qty_New", "Qty_Trans", "Notes"],
+    select: "SELECT [Item_schedule_id], [Item_Ref], [Room_Code], [Ignore_flag], [Qty_New], [Qty_Trans], [Notes] FROM Item_Schedule WITH (UPDLOCK, HOLDLOCK) WHERE [Item_schedule_id] = ?",
+};
+// Item_descriptions rows are shown joined to ERM, so one token covers both tables
+const ITEM_DESCRIPTION_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Item_descriptions",
+    key_column: "ADB_Ref",
+    columns: &["ADB_Ref", "Item_Description", "Unit_Cost", "Cat", "Group"],
+    select: "SELECT d.[ADB_Ref], d.[Item_Description], d.[Unit_Cost], e.[Cat], e.[Group] FROM Item_descriptions d WITH (UPDLOCK, HOLDLOCK) LEFT JOIN ERM e WITH (UPDLOCK, HOLDLOCK) ON d.[ADB_Ref] = e.[ADB_Code] WHERE d.[ADB_Ref] = ?",
+};
+// ERM edits are versioned with the joined Item_descriptions row but update ERM by ADB_Code
+const ERM_VERSION: RowVersionSpec = RowVersionSpec { table: "ERM", key_column: "ADB_Code", ..ITEM_DESCRIPTION_VERSION };
+const ROOM_TYPE_VERSION: RowVersionSpec = RowVersionSpec {
+    table: "Room_Types",
+    key_column: "Room_Code",
+    columns: &["Room_Code", "Room_Description", "Area"],
+    select: "SELECT [Room_Code], [Room_Description], [Area] FROM Room_Types WITH (UPDLOCK, HOLDLOCK) WHERE [Room_Code] = ?",
+};
+
+// Outcome of an update that may carry an `expected_version`
+enum VersionedUpdate {
+    Updated(Option<String>),
+    Conflict(AppError),
+}
+
+// FNV-1a over the cells; stable across builds so tokens survive restarts
+fn row_version(cells: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for cell in cells {
+        for byte in cell.as_ref().bytes().chain(std::iter::once(0x1f)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+impl TableData {
+    fn with_versions(mut self, spec: &RowVersionSpec) -> TableData {
+        let indexes: Vec<Option<usize>> = spec.columns.iter().map(|c| self.col(c)).collect();
+        self.versions = self.rows.iter()
+            .map(|row| row_version(indexes.iter().map(|i| i.and_then(|i| row.get(i)).map(cell_text).unwrap_or_default())))
+            .collect();
+        self
+    }
+}
+
+// Run the UPDATE `sql` only if the row still has the version the client last saw,
+// holding an update lock between the check and the write
+async fn versioned_update(
+    conn: &mut DbConn,
+    spec: &RowVersionSpec,
+    key: &str,
+    column: &str,
+    expected: Option<&str>,
+    sql: &str,
+    params: &[Option<&str>],
+) -> Result<VersionedUpdate, AppError> {
+    let missing = || missing_row(spec.table, spec.key_column, key);
+    let Some(expected) = expected else {
+        if execute_counted(conn, sql, params).await? == 0 {
+            return Err(missing());
+        }
+        return Ok(VersionedUpdate::Updated(None));
+    };
+    begin_transaction(conn).await?;
+    let outcome = async {
+        let current = query_table(conn, spec.select, &[Some(key)]).await?;
+        if !current.rows.iter().any(|r| row_version(r.iter().map(cell_text)) == expected) {
+            let row = current.rows.first();
+            let message = if row.is_some() { "Row was changed by someone else" } else { "Row no longer exists" };
+            return Ok(VersionedUpdate::Conflict(AppError::conflict(message).with_details(serde_json::json!({
+                "column": column,
+                "value": row.and_then(|r| current.get(r, column)),
+                "version": row.map(|r| row_version(r.iter().map(cell_text))),
+                "current": row.map(|r| row_object(&current, r)),
+            }))));
+        }
+        if execute_counted(conn, sql, params).await? == 0 {
+            return Err(missing());
+        }
+        // No new token when the key column itself was edited; the client reloads instead
+        let after = query_table(conn, spec.select, &[Some(key)]).await?;
+        Ok(VersionedUpdate::Updated(after.rows.first().map(|r| row_version(r.iter().map(cell_text)))))
+    }.await;
+    end_transaction(conn, matches!(outcome, Ok(VersionedUpdate::Updated(_)))).await?;
+    outcome
+}
+
+fn versioned_update_response(result: Result<VersionedUpdate, AppError>) -> axum::response::Response {
+    match result {
+        Ok(VersionedUpdate::Updated(None)) => axum::response::Response::new("OK".into()),
+        Ok(VersionedUpdate::Updated(Some(version))) => Json(serde_json::json!({ "version": version })).into_response(),
+        Ok(VersionedUpdate::Conflict(e)) => e.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchEdit {
+    key: String,
+    column: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    expected_version: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BatchUpdate {
+    edits: Vec<BatchEdit>,
+}
+
+#[derive(Serialize)]
+struct BatchEditResult {
+    index: usize,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conflict: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+// Where a batch of cell edits for one table goes
+struct BatchTable {
+    table: &'static str,
+    key_column: &'static str,
+    version: &'static RowVersionSpec,
+    // Columns the single-cell endpoint restricts edits to, if any
+    columns: Option<&'static [&'static str]>,
+}
+
+const AREASLEVEL3_BATCH: BatchTable = BatchTable { table: "Areaslevel3", key_column: "ParentArea", version: &AREASLEVEL3_VERSION, columns: None };
+const AREASLEVEL2_BATCH: BatchTable = BatchTable { table: "Areaslevel2", key_column: "ArealevelID", version: &AREASLEVEL2_VERSION, columns: None };
+const AREASLEVEL1_BATCH: BatchTable = BatchTable { table: "Areaslevel1", key_column: "ArealevelID", version: &AREASLEVEL1_VERSION, columns: None };
+const ROOM_SCHEDULE_BATCH: BatchTable = BatchTable { table: "Room_Schedule", key_column: "Room_Id", version: &ROOM_SCHEDULE_VERSION, columns: None };
+const ITEM_SCHEDULE_BATCH: BatchTable = BatchTable { table: "Item_Schedule", key_column: "Item_schedule_id", version: &ITEM_SCHEDULE_VERSION, columns: None };
+const ROOM_TYPE_BATCH: BatchTable = BatchTable { table: "Room_Types", key_column: "Room_Code", version: &ROOM_TYPE_VERSION, columns: None };
+const ITEM_DESCRIPTION_BATCH: BatchTable = BatchTable { table: "Item_descriptions", key_column: "ADB_Ref", version: &ITEM_DESCRIPTION_VERSION, columns: Some(&["Item_Description", "Unit_Cost"]) };
+const ERM_BATCH: BatchTable = BatchTable { table: "ERM", key_column: "ADB_Code", version: &ERM_VERSION, columns: Some(&["Cat", "Group"]) };
+
+// Apply one edit inside the caller's transaction
+async fn apply_batch_edit(conn: &mut DbConn, target: &BatchTable, meta: &TableMeta, index: usize, edit: &BatchEdit) -> BatchEditResult {
+    let failed = |e: AppError| BatchEditResult { index, ok: false, version: None, code: Some(e.kind.code()), error: Some(e.message), conflict: None, warning: None };
+    if let Some(columns) = target.columns {
+        if !columns.contains(&edit.column.as_str()) {
+            return failed(AppError::validation(format!("Only {} can be updated", columns.join(" and "))));
+        }
+    }
+    let value = match cell_param(&edit.value) {
+        Ok(v) => v,
+        Err(e) => return failed(e),
+    };
+    if let Some(expected) = &edit.expected_version {
+        let current = match query_table(conn, target.version.select, &[Some(&edit.key)]).await {
+            Ok(t) => t,
+            Err(e) => return failed(e),
+        };
+        if !current.rows.iter().any(|r| row_version(r.iter().map(cell_text)) == *expected) {
+            let row = current.rows.first();
+            return BatchEditResult {
+                index,
+                ok: false,
+                version: None,
+                code: Some(ErrorKind::Conflict.code()),
+                error: Some(if row.is_some() { "Row was changed by someone else" } else { "Row no longer exists" }.to_string()),
+                conflict: Some(serde_json::json!({
+                    "value": row.and_then(|r| current.get(r, &edit.column)),
+                    "version": row.map(|r| row_version(r.iter().map(cell_text))),
+                    "current": row.map(|r| row_object(&current, r)),
+                })),
+                warning: None,
             };
-            let sql = format!("UPDATE ERM SET [{}] = ? WHERE [ADB_Code] = ?", column.replace('"', ""));
-            let exec_result = conn.execute(&sql, (&value_cstr, &adb_ref_cstr));
-            match exec_result {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
+        }
+    }
+    let warning = match check_reference(conn, target.table, &edit.column, &cell_text(&edit.value)).await {
+        Ok(w) => w,
+        Err(e) => return failed(e),
+    };
+    let column = match meta.writable_column(&edit.column) {
+        Ok(c) => &c.name,
+        Err(e) => return failed(e),
+    };
+    let sql = format!("UPDATE {} SET [{}] = ? WHERE [{}] = ?", target.table, column, target.key_column);
+    match execute_counted(conn, &sql, &[value.as_deref(), Some(&edit.key)]).await {
+        Ok(0) => return failed(missing_row(target.table, target.key_column, &edit.key)),
+        Ok(_) => {}
+        Err(e) => return failed(e),
+    }
+    let version = query_table(conn, target.version.select, &[Some(&edit.key)]).await
+        .ok()
+        .and_then(|t| t.rows.first().map(|r| row_version(r.iter().map(cell_text))));
+    BatchEditResult { index, ok: true, version, code: None, error: None, conflict: None, warning }
+}
+
+// Apply every edit in one transaction: all of them are committed or none are
+async fn batch_update(target: &'static BatchTable, db_name: String, user: CurrentUser, edits: Vec<BatchEdit>) -> axum::response::Response {
+    if edits.is_empty() {
+        return Json(serde_json::json!({ "updated": 0, "results": [] })).into_response();
+    }
+    let changes: Vec<ChangeEvent> = edits.iter()
+        .map(|e| ChangeEvent {
+            key: Some(e.key.clone()),
+            column: Some(e.column.clone()),
+            value: Some(e.value.clone()),
+            ..ChangeEvent::new("update", target.table, &user)
+        })
+        .collect();
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let meta = table_meta(conn, target.table).await?;
+        begin_transaction(conn).await?;
+        // Keep going after a failure so every bad edit is reported at once
+        let mut results = Vec::with_capacity(edits.len());
+        for (i, e) in edits.iter().enumerate() {
+            results.push(apply_batch_edit(conn, target, &meta, i, e).await);
+        }
+        // Per-room rows are checked once every edit is in, so one batch can move
+        // a row's Room_Code and instance_variant together
+        if target.table == ITEM_SCHEDULE_BATCH.table {
+            for (r, e) in results.iter_mut().zip(&edits) {
+                if !r.ok || !is_room_instance_column(&e.column) {
+                    continue;
+                }
+                if let Err(err) = check_item_room_instance(conn, &e.key, None).await {
+                    *r = BatchEditResult { index: r.index, ok: false, version: None, code: Some(err.kind.code()), error: Some(err.message), conflict: None, warning: None };
+                }
             }
         }
+        end_transaction(conn, results.iter().all(|r| r.ok)).await?;
+        Ok(results)
+    })).await;
+    match result {
+        Ok(results) if results.iter().all(|r| r.ok) => {
+            for (change, r) in changes.into_iter().zip(&results) {
+                publish_change(&db_name, ChangeEvent { version: r.version.clone(), ..change });
+            }
+            Json(serde_json::json!({ "updated": results.len(), "results": results })).into_response()
+        }
+        Ok(results) => {
+            let kind = if results.iter().any(|r| r.conflict.is_some()) { ErrorKind::Conflict } else { ErrorKind::Validation };
+            let failed = results.iter().filter(|r| !r.ok).count();
+            let message = format!("{} of {} edits failed; nothing was saved", failed, results.len());
+            AppError::new(kind, message).with_details(serde_json::json!({ "results": results })).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn batch_update_areaslevel3(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&AREASLEVEL3_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_areaslevel2(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&AREASLEVEL2_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_areaslevel1(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&AREASLEVEL1_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_room_schedule(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&ROOM_SCHEDULE_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_item_schedule(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&ITEM_SCHEDULE_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_room_types(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&ROOM_TYPE_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_item_descriptions(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&ITEM_DESCRIPTION_BATCH, db_name, user, payload.edits).await
+}
+
+async fn batch_update_erm(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BatchUpdate>) -> axum::response::Response {
+    batch_update(&ERM_BATCH, db_name, user, payload.edits).await
+}
+
+#[derive(Serialize, Clone)]
+struct ColumnInfo {
+    name: String,
+    data_type: String,
+    nullable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_length: Option<i64>,
+    identity: bool,
+    computed: bool,
+}
+
+#[derive(Serialize)]
+struct TableMeta {
+    table: String,
+    // Column that addresses a row for update/delete; None leaves the table read-only
+    primary_key: Option<String>,
+    column_info: Vec<ColumnInfo>,
+}
+
+impl TableMeta {
+    fn column(&self, name: &str) -> Result<&ColumnInfo, AppError> {
+        self.column_info.iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| AppError::validation(format!("{} has no column {}", self.table, name)))
+    }
+
+    fn writable_column(&self, name: &str) -> Result<&ColumnInfo, AppError> {
+        let column = self.column(name)?;
+        if column.identity || column.computed {
+            return Err(AppError::validation(format!("{} is generated by the database", column.name)));
+        }
+        Ok(column)
+    }
+
+    fn key(&self) -> Result<&str, AppError> {
+        self.primary_key.as_deref().ok_or_else(|| AppError::validation(format!("{} has no single-column primary key", self.table)))
+    }
+}
+
+#[derive(Serialize)]
+struct BrowseTable {
+    #[serde(flatten)]
+    meta: TableMeta,
+    #[serde(flatten)]
+    data: TableData,
+}
+
+#[derive(Deserialize)]
+struct BrowseUpdate {
+    key: String,
+    column: String,
+    value: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct BrowseDelete {
+    key: String,
+}
+
+// The allowlisted spelling of a table, or None if the browser may not open it
+fn browse_table_name(table: &str) -> Option<String> {
+    CONFIG.browse_tables.iter().find(|t| t.eq_ignore_ascii_case(table)).cloned()
+}
+
+// The stored spelling of a column the client may write in `table`. Every UPDATE and
+// INSERT names its columns through this or TableMeta::writable_column, so anything
+// that is not one of the table's own columns is refused rather than cleaned up.
+async fn table_column(conn: &mut DbConn, table: &str, column: &str) -> Result<String, AppError> {
+    Ok(table_meta(conn, table).await?.writable_column(column)?.name.clone())
+}
+
+async fn table_meta(conn: &mut DbConn, table: &str) -> Result<TableMeta, AppError> {
+    let columns = query_table(conn, "SELECT c.COLUMN_NAME, c.DATA_TYPE, c.IS_NULLABLE, c.CHARACTER_MAXIMUM_LENGTH, \
+        COLUMNPROPERTY(OBJECT_ID(QUOTENAME(c.TABLE_SCHEMA) + '.' + QUOTENAME(c.TABLE_NAME)), c.COLUMN_NAME, 'IsIdentity'), \
+        COLUMNPROPERTY(OBJECT_ID(QUOTENAME(c.TABLE_SCHEMA) + '.' + QUOTENAME(c.TABLE_NAME)), c.COLUMN_NAME, 'IsComputed') \
+        FROM INFORMATION_SCHEMA.COLUMNS c WHERE c.TABLE_NAME = ? ORDER BY c.ORDINAL_POSITION", &[Some(table)]).await?;
+    if columns.rows.is_empty() {
+        return Err(AppError::not_found(format!("Table {} not found", table)));
+    }
+    let column_info: Vec<ColumnInfo> = columns.rows.iter()
+        .map(|r| ColumnInfo {
+            name: cell_text(&r[0]).into_owned(),
+            data_type: cell_text(&r[1]).into_owned(),
+            nullable: cell_text(&r[2]) == "YES",
+            max_length: r[3].as_i64(),
+            identity: cell_text(&r[4]) == "1",
+            computed: cell_text(&r[5]) == "1",
+        })
+        .collect();
+    let keys = query_table(conn, "SELECT k.COLUMN_NAME FROM INFORMATION_SCHEMA.TABLE_CONSTRAINTS t \
+        JOIN INFORMATION_SCHEMA.KEY_COLUMN_USAGE k ON k.CONSTRAINT_NAME = t.CONSTRAINT_NAME AND k.TABLE_SCHEMA = t.TABLE_SCHEMA \
+        WHERE t.CONSTRAINT_TYPE = 'PRIMARY KEY' AND t.TABLE_NAME = ? ORDER BY k.ORDINAL_POSITION", &[Some(table)]).await?;
+    // Fall back to the identity column for tables created without a primary key
+    let primary_key = match keys.rows.as_slice() {
+        [key] => Some(cell_text(&key[0]).into_owned()),
+        [] => column_info.iter().find(|c| c.identity).map(|c| c.name.clone()),
+        _ => None,
+    };
+    Ok(TableMeta { table: table.to_string(), primary_key, column_info })
+}
+
+fn not_browsable(table: &str) -> axum::response::Response {
+    AppError::not_found(format!("Table {} is not available in the table browser", table)).into_response()
+}
+
+// Handler for the generic table page
+async fn browse_page(Path((_db_name, _table)): Path<(String, String)>) -> impl IntoResponse {
+    Html(include_str!("../static/table.html"))
+}
+
+async fn list_browse_tables(Path(_db_name): Path<String>) -> axum::response::Response {
+    Json(&CONFIG.browse_tables).into_response()
+}
+
+async fn get_browse_table(Path((db_name, table)): Path<(String, String)>) -> axum::response::Response {
+    let Some(table) = browse_table_name(&table) else {
+        return not_browsable(&table);
+    };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let meta = table_meta(conn, &table).await?;
+        let order = meta.primary_key.as_ref().map(|k| format!(" ORDER BY [{}]", k)).unwrap_or_default();
+        let data = query_table(conn, &format!("SELECT * FROM [{}]{}", meta.table, order), &[]).await?;
+        Ok(BrowseTable { meta, data })
+    })).await;
+    match result {
+        Ok(table) => Json(table).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn add_browse_row(Path((db_name, table)): Path<(String, String)>, Extension(user): Extension<CurrentUser>, Json(payload): Json<serde_json::Value>) -> axum::response::Response {
+    let Some(table) = browse_table_name(&table) else {
+        return not_browsable(&table);
+    };
+    let change = ChangeEvent::new("add", &table, &user);
+    let warning = match row_reference_warning(&db_name, &table, &payload).await {
+        Ok(w) => w,
+        Err(e) => return e.into_response(),
+    };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let meta = table_meta(conn, &table).await?;
+        let obj = payload.as_object().ok_or_else(|| AppError::validation("Invalid row data"))?;
+        let mut columns = Vec::new();
+        let mut values = Vec::new();
+        for (name, value) in obj {
+            columns.push(meta.writable_column(name)?.name.clone());
+            values.push(cell_param(value)?);
+        }
+        match meta.primary_key.as_deref() {
+            Some(key) => insert_returning(conn, &meta.table, key, &columns, &values).await,
+            // Without a key the row cannot be read back, so echo what was written
+            None => {
+                let quoted: Vec<String> = columns.iter().map(|c| format!("[{}]", c)).collect();
+                let sql = format!("INSERT INTO [{}] ({}) VALUES ({})", meta.table, quoted.join(", "), vec!["?"; columns.len()].join(", "));
+                let params: Vec<_> = values.iter().map(|v| v.as_deref()).collect();
+                execute_counted(conn, &sql, &params).await?;
+                Ok(obj.clone())
+            }
+        }
+    })).await;
+    match result {
+        Ok(row) => {
+            publish_change(&db_name, ChangeEvent { row: Some(serde_json::Value::Object(row.clone())), ..change });
+            with_reference_warning(Json(row).into_response(), warning)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn update_browse_cell(Path((db_name, table)): Path<(String, String)>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BrowseUpdate>) -> axum::response::Response {
+    let Some(table) = browse_table_name(&table) else {
+        return not_browsable(&table);
+    };
+    let BrowseUpdate { key, column, value } = payload;
+    let change = ChangeEvent {
+        key: Some(key.clone()),
+        column: Some(column.clone()),
+        value: Some(value.clone()),
+        ..ChangeEvent::new("update", &table, &user)
+    };
+    let warning = match reference_warning(&db_name, &table, &column, &cell_text(&value)).await {
+        Ok(w) => w,
+        Err(e) => return e.into_response(),
+    };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let meta = table_meta(conn, &table).await?;
+        let column = meta.writable_column(&column)?;
+        let value = cell_param(&value)?;
+        let sql = format!("UPDATE [{}] SET [{}] = ? WHERE [{}] = ?", meta.table, column.name, meta.key()?);
+        if execute_counted(conn, &sql, &[value.as_deref(), Some(key.as_str())]).await? == 0 {
+            return Err(missing_row(&meta.table, meta.key()?, &key));
+        }
+        Ok(())
+    })).await;
+    match result {
+        Ok(()) => {
+            publish_change(&db_name, change);
+            with_reference_warning(axum::response::Response::new("OK".into()), warning)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn delete_browse_row(Path((db_name, table)): Path<(String, String)>, Extension(user): Extension<CurrentUser>, Json(payload): Json<BrowseDelete>) -> axum::response::Response {
+    let Some(table) = browse_table_name(&table) else {
+        return not_browsable(&table);
+    };
+    let BrowseDelete { key } = payload;
+    let change = ChangeEvent { key: Some(key.clone()), ..ChangeEvent::new("delete", &table, &user) };
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        set_session_user(conn, &user).await?;
+        let meta = table_meta(conn, &table).await?;
+        let sql = format!("DELETE FROM [{}] WHERE [{}] = ?", meta.table, meta.key()?);
+        if execute_counted(conn, &sql, &[Some(key.as_str())]).await? == 0 {
+            return Err(missing_row(&meta.table, meta.key()?, &key));
+        }
+        Ok(())
+    })).await;
+    match result {
+        Ok(()) => {
+            publish_change(&db_name, change);
+            axum::response::Response::new("OK".into())
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// What happens when a Room_Code or Item_Ref names nothing in the library tables
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ReferenceMode {
+    // Refuse the change with 422
+    Strict,
+    // Save it and answer an `x-reference-warning` header
+    Warn,
+}
+
+// A schedule column whose values must exist in a library table
+struct Reference {
+    table: &'static str,
+    column: &'static str,
+    library: &'static str,
+    library_column: &'static str,
+}
+
+const REFERENCES: [Reference; 2] = [
+    Reference { table: "Room_Schedule", column: "Room_Code", library: "Room_Types", library_column: "Room_Code" },
+    Reference { table: "Item_Schedule", column: "Item_Ref", library: "Item_descriptions", library_column: "ADB_Ref" },
+];
+
+const REFERENCE_WARNING: &str = "x-reference-warning";
+
+fn reference_for(table: &str, column: &str) -> Option<&'static Reference> {
+    REFERENCES.iter().find(|r| r.table.eq_ignore_ascii_case(table) && r.column.eq_ignore_ascii_case(column))
+}
+
+// Ok(Some(warning)) in warn mode, Err in strict mode, Ok(None) when the value is known or blank
+async fn check_reference(conn: &mut DbConn, table: &str, column: &str, value: &str) -> Result<Option<String>, AppError> {
+    let Some(reference) = reference_for(table, column) else {
+        return Ok(None);
+    };
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    let sql = format!("SELECT COUNT(*) FROM [{}] WHERE [{}] = ?", reference.library, reference.library_column);
+    let found = query_table(conn, &sql, &[Some(value)]).await?;
+    if found.rows.first().and_then(|r| r.first()).and_then(|n| n.as_u64()).is_some_and(|n| n > 0) {
+        return Ok(None);
+    }
+    let message = format!("{} {} is not in {}", reference.column, value, reference.library);
+    match CONFIG.reference_mode {
+        ReferenceMode::Strict => Err(AppError::validation(message).with_details(serde_json::json!({
+            "column": reference.column,
+            "value": value,
+            "library": reference.library,
+        }))),
+        ReferenceMode::Warn => Ok(Some(message)),
+    }
+}
+
+// Run check_reference on its own pooled connection ahead of the write
+async fn reference_warning(db_name: &str, table: &str, column: &str, value: &str) -> Result<Option<String>, AppError> {
+    if reference_for(table, column).is_none() {
+        return Ok(None);
+    }
+    let (table, column, value) = (table.to_string(), column.to_string(), value.to_string());
+    with_connection(db_name, move |conn| Box::pin(async move { check_reference(conn, &table, &column, &value).await })).await
+}
+
+// Check every referenced column present in an add payload
+async fn row_reference_warning(db_name: &str, table: &str, row: &serde_json::Value) -> Result<Option<String>, AppError> {
+    let mut warnings = Vec::new();
+    for reference in REFERENCES.iter().filter(|r| r.table.eq_ignore_ascii_case(table)) {
+        if let Some(value) = row.get(reference.column).and_then(|v| v.as_str()) {
+            warnings.extend(reference_warning(db_name, table, reference.column, value).await?);
+        }
+    }
+    Ok((!warnings.is_empty()).then(|| warnings.join("; ")))
+}
+
+fn with_reference_warning(mut response: axum::response::Response, warning: Option<String>) -> axum::response::Response {
+    if let Some(warning) = warning {
+        let ascii: String = warning.chars().map(|c| if c.is_ascii_graphic() || c == ' ' { c } else { '?' }).collect();
+        if let Ok(value) = axum::http::HeaderValue::from_str(&ascii) {
+            response.headers_mut().insert(REFERENCE_WARNING, value);
+        }
+    }
+    response
+}
+
+// Existing rows whose codes name nothing in the library tables
+async fn reference_report(Path(db_name): Path<String>) -> axum::response::Response {
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let mut report = Vec::new();
+        for reference in &REFERENCES {
+            let sql = format!(
+                "SELECT t.* FROM [{}] t WHERE ISNULL(t.[{}], '') <> '' AND NOT EXISTS (SELECT 1 FROM [{}] l WHERE l.[{}] = t.[{}])",
+                reference.table, reference.column, reference.library, reference.library_column, reference.column
+            );
+            let rows = query_table(conn, &sql, &[]).await?;
+            report.push(serde_json::json!({
+                "table": reference.table,
+                "column": reference.column,
+                "library": reference.library,
+                "count": rows.rows.len(),
+                "columns": rows.columns,
+                "rows": rows.rows,
+            }));
+        }
+        Ok(report)
+    })).await;
+    match result {
+        Ok(report) => Json(serde_json::json!({ "mode": CONFIG.reference_mode, "references": report })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// A repair offered next to a failed check; runs as one statement
+struct IntegrityFix {
+    id: &'static str,
+    label: &'static str,
+    access: Access,
+    sql: &'static str,
+}
+
+// One health check: `sql` selects the offending rows
+struct IntegrityCheck {
+    id: &'static str,
+    category: &'static str,
+    title: &'static str,
+    table: &'static str,
+    sql: &'static str,
+    // What to do by hand when there is no automatic fix
+    advice: &'static str,
+    fixes: &'static [IntegrityFix],
+}
+
+// Treat a column as text so numeric and text columns are checked the same way
+macro_rules! as_number {
+    ($col:literal) => {
+        concat!("TRY_CONVERT(float, CONVERT(nvarchar(100), ", $col, "))")
+    };
+}
+pub(crate) use as_number;
+
+macro_rules! not_numeric {
+    ($col:literal) => {
+        concat!("(LTRIM(ISNULL(CONVERT(nvarchar(100), ", $col, "), '')) <> '' AND ", as_number!($col), " IS NULL)")
+    };
+}
+
+const INTEGRITY_CHECKS: &[IntegrityCheck] = &[
+    IntegrityCheck {
+        id: "orphan_areaslevel2",
+        category: "Area hierarchy",
+        title: "Areaslevel2 rows whose ParentArea is not an Areaslevel3 area",
+        table: "Areaslevel2",
+        sql: "SELECT a.* FROM Areaslevel2 a WHERE NOT EXISTS (SELECT 1 FROM Areaslevel3 p WHERE p.[ArealevelID] = a.[ParentArea])",
+        advice: "Set ParentArea to an existing Areaslevel3 ArealevelID, or delete the area.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "orphan_areaslevel1",
+        category: "Area hierarchy",
+        title: "Areaslevel1 rows whose ParentArea is not an Areaslevel2 area",
+        table: "Areaslevel1",
+        sql: "SELECT a.* FROM Areaslevel1 a WHERE NOT EXISTS (SELECT 1 FROM Areaslevel2 p WHERE p.[ArealevelID] = a.[ParentArea])",
+        advice: "Set ParentArea to an existing Areaslevel2 ArealevelID, or delete the area.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "rooms_without_area",
+        category: "Rooms",
+        title: "Rooms whose ParentArea is not an Areaslevel1 area",
+        table: "Room_Schedule",
+        sql: "SELECT r.* FROM Room_Schedule r WHERE NOT EXISTS (SELECT 1 FROM Areaslevel1 a WHERE a.[ArealevelID] = r.[ParentArea])",
+        advice: "Move the rooms to an existing Areaslevel1 area on the Room_Schedule page.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "duplicate_room_ids",
+        category: "Rooms",
+        title: "Room_Id values used by more than one room",
+        table: "Room_Schedule",
+        sql: "SELECT r.* FROM Room_Schedule r WHERE r.[Room_Id] IN (SELECT [Room_Id] FROM Room_Schedule GROUP BY [Room_Id] HAVING COUNT(*) > 1) ORDER BY r.[Room_Id]",
+        advice: "Give each room a unique Room_Id; edits and deletes by Room_Id touch every duplicate.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "bad_room_areas",
+        category: "Quantities and areas",
+        title: "Rooms with a negative or non-numeric Area",
+        table: "Room_Schedule",
+        sql: concat!("SELECT r.* FROM Room_Schedule r WHERE ", as_number!("r.[Area]"), " < 0 OR ", not_numeric!("r.[Area]")),
+        advice: "Correct the Area on the Room_Schedule page.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "bad_room_type_areas",
+        category: "Quantities and areas",
+        title: "Room types with a negative or non-numeric Area",
+        table: "Room_Types",
+        sql: concat!("SELECT t.* FROM Room_Types t WHERE ", as_number!("t.[Area]"), " < 0 OR ", not_numeric!("t.[Area]")),
+        advice: "Correct the Area on the Room Types page.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "negative_quantities",
+        category: "Quantities and areas",
+        title: "Item_Schedule rows with a negative Qty_New or Qty_Trans",
+        table: "Item_Schedule",
+        sql: concat!("SELECT s.* FROM Item_Schedule s WHERE ", as_number!("s.[Qty_New]"), " < 0 OR ", as_number!("s.[Qty_Trans]"), " < 0"),
+        advice: "Correct the quantities on the Item_Schedule page.",
+        fixes: &[IntegrityFix {
+            id: "zero_negative_quantities",
+            label: "Set negative quantities to 0",
+            access: Access::EditSchedule,
+            sql: concat!(
+                "UPDATE Item_Schedule SET [Qty_New] = CASE WHEN ", as_number!("[Qty_New]"), " < 0 THEN 0 ELSE [Qty_New] END, ",
+                "[Qty_Trans] = CASE WHEN ", as_number!("[Qty_Trans]"), " < 0 THEN 0 ELSE [Qty_Trans] END ",
+                "WHERE ", as_number!("[Qty_New]"), " < 0 OR ", as_number!("[Qty_Trans]"), " < 0"
+            ),
+        }],
+    },
+    IntegrityCheck {
+        id: "non_numeric_quantities",
+        category: "Quantities and areas",
+        title: "Item_Schedule rows with a non-numeric Qty_New or Qty_Trans",
+        table: "Item_Schedule",
+        sql: concat!("SELECT s.* FROM Item_Schedule s WHERE ", not_numeric!("s.[Qty_New]"), " OR ", not_numeric!("s.[Qty_Trans]")),
+        advice: "Replace the text with a number on the Item_Schedule page.",
+        fixes: &[],
+    },
+    IntegrityCheck {
+        id: "items_without_room",
+        category: "Equipment",
+        title: "Item_Schedule rows whose Room_Code is used by no room",
+        table: "Item_Schedule",
+        sql: "SELECT s.* FROM Item_Schedule s WHERE NOT EXISTS (SELECT 1 FROM Room_Schedule r WHERE r.[Room_Code] = s.[Room_Code])",
+        advice: "Assign the Room_Code to a room, or remove the unused equipment list.",
+        fixes: &[IntegrityFix {
+            id: "delete_items_without_room",
+            label: "Delete these Item_Schedule rows",
+            access: Access::EditSchedule,
+            sql: "DELETE s FROM Item_Schedule s WHERE NOT EXISTS (SELECT 1 FROM Room_Schedule r WHERE r.[Room_Code] = s.[Room_Code])",
+        }],
+    },
+    IntegrityCheck {
+        id: "stale_room_items",
+        category: "Equipment",
+        title: "Per-room Item_Schedule rows whose room is gone or has another Room_Code",
+        table: "Item_Schedule",
+        sql: "SELECT s.* FROM Item_Schedule s WHERE ISNULL(s.[instance_variant], 0) <> 0 AND NOT EXISTS (SELECT 1 FROM Room_Schedule r WHERE CONVERT(nvarchar(100), r.[Room_Id]) = CONVERT(nvarchar(100), s.[instance_variant]) AND r.[Room_Code] = s.[Room_Code])",
+        advice: "These rows no longer apply to any room. Re-create the overrides on the room's item page if they are still wanted.",
+        fixes: &[IntegrityFix {
+            id: "delete_stale_room_items",
+            label: "Delete these Item_Schedule rows",
+            access: Access::EditSchedule,
+            sql: "DELETE s FROM Item_Schedule s WHERE ISNULL(s.[instance_variant], 0) <> 0 AND NOT EXISTS (SELECT 1 FROM Room_Schedule r WHERE CONVERT(nvarchar(100), r.[Room_Id]) = CONVERT(nvarchar(100), s.[instance_variant]) AND r.[Room_Code] = s.[Room_Code])",
+        }],
+    },
+    IntegrityCheck {
+        id: "items_without_erm",
+        category: "Equipment",
+        title: "Item_descriptions without an ERM entry",
+        table: "Item_descriptions",
+        sql: "SELECT d.* FROM Item_descriptions d WHERE NOT EXISTS (SELECT 1 FROM ERM e WHERE e.[ADB_Code] = d.[ADB_Ref])",
+        advice: "Fill in Cat and Group on the Item Descriptions page once the ERM entries exist.",
+        fixes: &[IntegrityFix {
+            id: "create_erm_entries",
+            label: "Create empty ERM entries",
+            access: Access::EditLibrary,
+            sql: "INSERT INTO ERM ([ADB_Code]) SELECT d.[ADB_Ref] FROM Item_descriptions d WHERE NOT EXISTS (SELECT 1 FROM ERM e WHERE e.[ADB_Code] = d.[ADB_Ref])",
+        }],
+    },
+];
+
+#[derive(Deserialize)]
+struct IntegrityFixRequest {
+    check: String,
+    fix: String,
+}
+
+// Page for the project health check
+async fn integrity_page(Path(_db_name): Path<String>) -> impl IntoResponse {
+    Html(include_str!("../static/integrity.html"))
+}
+
+async fn integrity_report(Path(db_name): Path<String>) -> axum::response::Response {
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let mut checks = Vec::new();
+        for check in INTEGRITY_CHECKS {
+            let found = query_table(conn, check.sql, &[]).await?;
+            let fixes: Vec<_> = check.fixes.iter().map(|f| serde_json::json!({ "id": f.id, "label": f.label })).collect();
+            checks.push(serde_json::json!({
+                "id": check.id,
+                "category": check.category,
+                "title": check.title,
+                "table": check.table,
+                "count": found.rows.len(),
+                "advice": check.advice,
+                "fixes": fixes,
+                "columns": found.columns,
+                "rows": found.rows,
+            }));
+        }
+        Ok(checks)
+    })).await;
+    match result {
+        Ok(checks) => {
+            let problems: usize = checks.iter().map(|c| c["count"].as_u64().unwrap_or(0) as usize).sum();
+            Json(serde_json::json!({ "database": db_name, "checked_at": unix_now(), "problems": problems, "checks": checks })).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// A check's fix by their ids
+fn integrity_fix(check: &str, fix: &str) -> Option<(&'static IntegrityCheck, &'static IntegrityFix)> {
+    INTEGRITY_CHECKS.iter()
+        .filter(|c| c.id == check)
+        .find_map(|c| c.fixes.iter().find(|f| f.id == fix).map(|f| (c, f)))
+}
+
+async fn apply_integrity_fix(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<IntegrityFixRequest>) -> axum::response::Response {
+    let Some((check, fix)) = integrity_fix(&payload.check, &payload.fix) else {
+        return AppError::not_found(format!("No fix {} for check {}", payload.fix, payload.check)).into_response();
+    };
+    // The route lets any editor in; each fix needs its own access
+    let roles = user_roles(&user.username, &db_name).await;
+    if !roles.iter().any(|r| role_allows(*r, fix.access)) {
+        return AppError::new(ErrorKind::Forbidden, format!("{} does not have {:?} permission on {}", user.username, fix.access, db_name)).into_response();
+    }
+    let result = with_connection(&db_name, {
+        let user = user.clone();
+        move |conn| Box::pin(async move {
+            set_session_user(conn, &user).await?;
+            execute_counted(conn, fix.sql, &[]).await
+        })
     }).await;
     match result {
-        Ok(Ok(())) => axum::response::Response::new("OK".into()),
-        Ok(Err(e)) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Ok(affected) => {
+            // Open pages reload rather than patch cells one by one
+            let table = if fix.access == Access::EditLibrary { "ERM" } else { check.table };
+            publish_change(&db_name, ChangeEvent::new("resync", table, &user));
+            Json(serde_json::json!({ "check": check.id, "fix": fix.id, "affected": affected })).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+impl TableData {
+    fn col(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.eq_ignore_ascii_case(name))
+    }
+
+    fn cell<'a>(&self, row: &'a [serde_json::Value], name: &str) -> std::borrow::Cow<'a, str> {
+        self.col(name).and_then(|i| row.get(i)).map(cell_text).unwrap_or_default()
+    }
+
+    fn get<'a>(&self, row: &'a [serde_json::Value], name: &str) -> Option<&'a serde_json::Value> {
+        self.col(name).and_then(|i| row.get(i))
+    }
+
+    // A numeric cell, whether the column was typed as a number or holds numeric text; otherwise 0
+    fn number(&self, row: &[serde_json::Value], name: &str) -> f64 {
+        match self.col(name).and_then(|i| row.get(i)) {
+            Some(serde_json::Value::Number(n)) => n.as_f64().unwrap_or(0.0),
+            Some(serde_json::Value::String(s)) => s.trim().parse().unwrap_or(0.0),
+            _ => 0.0,
+        }
+    }
+}
+
+// Compare keys the way SQL Server's default collation does
+fn same_key(a: &str, b: &str) -> bool {
+    a.trim_end().eq_ignore_ascii_case(b.trim_end())
+}
+
+// Keep names safe to use as file and directory names
+fn safe_file_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ' ' { c } else { '_' }).collect()
+}
+
+// How a result column is turned into JSON
+#[cfg(feature = "odbc")]
+#[derive(Clone, Copy)]
+enum ColumnKind {
+    Number,
+    Text,
+}
+
+#[cfg(feature = "odbc")]
+fn column_kind(data_type: odbc_api::DataType) -> ColumnKind {
+    use odbc_api::DataType;
+    match data_type {
+        DataType::Integer | DataType::SmallInt | DataType::TinyInt | DataType::BigInt | DataType::Bit
+        | DataType::Real | DataType::Float { .. } | DataType::Double
+        | DataType::Decimal { .. } | DataType::Numeric { .. } => ColumnKind::Number,
+        _ => ColumnKind::Text,
+    }
+}
+
+#[cfg(feature = "odbc")]
+fn typed_cell(kind: ColumnKind, text: String) -> serde_json::Value {
+    match kind {
+        ColumnKind::Number => number_cell(text),
+        ColumnKind::Text => serde_json::Value::String(text),
+    }
+}
+
+// A number column's text as a JSON number; text it could not parse is kept as text
+fn number_cell(text: String) -> serde_json::Value {
+    if let Ok(n) = text.trim().parse::<i64>() {
+        return serde_json::Value::from(n);
+    }
+    if let Ok(f) = text.trim().parse::<f64>() {
+        return json_number(f);
+    }
+    serde_json::Value::String(text)
+}
+
+// Whole numbers stay integers, so a DECIMAL 12.00 is sent as 12
+fn json_number(f: f64) -> serde_json::Value {
+    if f.fract() == 0.0 && f.abs() < 9_007_199_254_740_992.0 {
+        return serde_json::Value::from(f as i64);
+    }
+    serde_json::Number::from_f64(f).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null)
+}
+
+// A cell as text, for keys, comparisons and arithmetic; NULL reads as ""
+fn cell_text(value: &serde_json::Value) -> std::borrow::Cow<'_, str> {
+    match value {
+        serde_json::Value::String(s) => std::borrow::Cow::Borrowed(s),
+        serde_json::Value::Null => std::borrow::Cow::Borrowed(""),
+        other => std::borrow::Cow::Owned(other.to_string()),
+    }
+}
+
+// Run a query and hand each row to `each` as it is fetched, returning the column names.
+// Parameters are bound as text, or NULL for None; SQL Server converts them to the column's type.
+async fn for_each_row(
+    conn: &mut DbConn,
+    query: &str,
+    params: &[Option<&str>],
+    each: impl FnMut(Vec<serde_json::Value>) -> Result<(), AppError> + Send,
+) -> Result<Vec<String>, AppError> {
+    let started = Instant::now();
+    let result = match conn {
+        // ODBC calls block, so they run in place of this worker's other tasks
+        #[cfg(feature = "odbc")]
+        DbConn::Odbc(conn) => tokio::task::block_in_place(|| odbc_for_each_row(conn, query, params, each)),
+        #[cfg(feature = "tiberius")]
+        DbConn::Tiberius(client) => tiberius_for_each_row(client, query, params, each).await,
+    };
+    query_done(query, started.elapsed());
+    result
+}
+
+// Cells are read one at a time as wide text, so NVARCHAR(MAX) values arrive whole and NULL stays null
+#[cfg(feature = "odbc")]
+fn odbc_for_each_row(
+    conn: &Connection<'static>,
+    query: &str,
+    params: &[Option<&str>],
+    mut each: impl FnMut(Vec<serde_json::Value>) -> Result<(), AppError>,
+) -> Result<Vec<String>, AppError> {
+    let params: Vec<_> = params.iter().map(|p| p.into_parameter()).collect();
+    let Some(mut cursor) = conn.execute(query, params.as_slice())? else {
+        return Ok(vec![]);
+    };
+    let columns: Vec<String> = cursor.column_names()?.map(|n| n.unwrap_or_default()).collect();
+    let kinds = (1..=columns.len() as u16)
+        .map(|i| cursor.col_data_type(i).map(column_kind))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut buf = Vec::new();
+    while let Some(mut row) = cursor.next_row()? {
+        let mut values = Vec::with_capacity(kinds.len());
+        for (i, kind) in kinds.iter().enumerate() {
+            values.push(match row.get_wide_text(i as u16 + 1, &mut buf)? {
+                true => typed_cell(*kind, String::from_utf16_lossy(&buf)),
+                false => serde_json::Value::Null,
+            });
+        }
+        each(values)?;
+    }
+    Ok(columns)
+}
+
+// Only the first result set is read, as with ODBC, so batches like insert_returning's work unchanged
+#[cfg(feature = "tiberius")]
+async fn tiberius_for_each_row(
+    client: &mut TiberiusClient,
+    query: &str,
+    params: &[Option<&str>],
+    mut each: impl FnMut(Vec<serde_json::Value>) -> Result<(), AppError> + Send,
+) -> Result<Vec<String>, AppError> {
+    use tokio_stream::StreamExt;
+    let params: Vec<&dyn tiberius::ToSql> = params.iter().map(|p| p as &dyn tiberius::ToSql).collect();
+    let mut stream = client.query(tiberius_sql(query), &params).await?;
+    let mut first: Option<(usize, Vec<String>)> = None;
+    while let Some(item) = stream.next().await {
+        match item? {
+            tiberius::QueryItem::Metadata(meta) if first.is_none() => {
+                first = Some((meta.result_index(), meta.columns().iter().map(|c| c.name().to_string()).collect()));
+            }
+            tiberius::QueryItem::Row(row) if first.as_ref().is_some_and(|(index, _)| *index == row.result_index()) => {
+                each(row.into_iter().map(tiberius_cell).collect())?;
+            }
+            _ => {}
+        }
+    }
+    Ok(first.map(|(_, columns)| columns).unwrap_or_default())
+}
+
+// tiberius numbers its placeholders @P1, @P2, ... where ODBC takes `?`
+#[cfg(feature = "tiberius")]
+fn tiberius_sql(query: &str) -> String {
+    let mut sql = String::with_capacity(query.len() + 16);
+    let mut next = 0;
+    let mut quote = None;
+    for c in query.chars() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '?') => {
+                next += 1;
+                sql.push_str(&format!("@P{next}"));
+                continue;
+            }
+            (Some(end), c) if c == end => quote = None,
+            _ => {}
+        }
+        sql.push(c);
+    }
+    sql
+}
+
+// Typed the same way as ODBC text through typed_cell: numbers, strings, or null
+#[cfg(feature = "tiberius")]
+fn tiberius_cell(data: tiberius::ColumnData<'static>) -> serde_json::Value {
+    use serde_json::Value;
+    use tiberius::ColumnData;
+    match data {
+        ColumnData::U8(v) => v.into(),
+        ColumnData::I16(v) => v.into(),
+        ColumnData::I32(v) => v.into(),
+        ColumnData::I64(v) => v.into(),
+        ColumnData::F32(v) => v.map_or(Value::Null, |f| number_cell(f.to_string())),
+        ColumnData::F64(v) => v.map_or(Value::Null, json_number),
+        ColumnData::Bit(v) => v.map_or(Value::Null, |b| Value::from(b as u8)),
+        ColumnData::Numeric(v) => v.map_or(Value::Null, |n| number_cell(n.to_string())),
+        ColumnData::String(v) => v.map_or(Value::Null, |s| Value::String(s.into_owned())),
+        ColumnData::Guid(v) => v.map_or(Value::Null, |g| Value::String(g.to_string().to_uppercase())),
+        ColumnData::Binary(v) => v.map_or(Value::Null, |b| Value::String(b.iter().map(|byte| format!("{byte:02X}")).collect())),
+        ColumnData::Xml(v) => v.map_or(Value::Null, |x| Value::String(x.to_string())),
+        ColumnData::DateTime(v) => v.map_or(Value::Null, |d| {
+            // Days from 1900-01-01 and 1/300ths of a second
+            let ms = d.seconds_fragments() as u64 * 10 / 3;
+            Value::String(format!("{} {}.{:03}", sql_date(d.days() as i64 + DAYS_TO_1900), clock(ms / 1000), ms % 1000))
+        }),
+        ColumnData::SmallDateTime(v) => v.map_or(Value::Null, |d| {
+            Value::String(format!("{} {}", sql_date(d.days() as i64 + DAYS_TO_1900), clock(d.seconds_fragments() as u64 * 60)))
+        }),
+        ColumnData::Date(v) => v.map_or(Value::Null, |d| Value::String(sql_date(d.days() as i64))),
+        ColumnData::Time(v) => v.map_or(Value::Null, |t| Value::String(sql_time(t))),
+        ColumnData::DateTime2(v) => v.map_or(Value::Null, |d| Value::String(format!("{} {}", sql_date(d.date().days() as i64), sql_time(d.time())))),
+        ColumnData::DateTimeOffset(v) => v.map_or(Value::Null, |d| {
+            let (dt, offset) = (d.datetime2(), d.offset());
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.unsigned_abs();
+            Value::String(format!("{} {} {sign}{:02}:{:02}", sql_date(dt.date().days() as i64), sql_time(dt.time()), offset / 60, offset % 60))
+        }),
+    }
+}
+
+// Days from 0001-01-01 to 1900-01-01, the two epochs SQL Server dates count from
+#[cfg(feature = "tiberius")]
+const DAYS_TO_1900: i64 = 693_595;
+
+// YYYY-MM-DD for a day count from 0001-01-01 (proleptic Gregorian)
+#[cfg(feature = "tiberius")]
+fn sql_date(days: i64) -> String {
+    // Shift to days from 0000-03-01 so leap days fall at the end of each year
+    let z = days + 306;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + if month <= 2 { 1 } else { 0 };
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(feature = "tiberius")]
+fn clock(seconds: u64) -> String {
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// HH:MM:SS with the column's fractional digits
+#[cfg(feature = "tiberius")]
+fn sql_time(time: tiberius::time::Time) -> String {
+    let scale = 10u64.pow(time.scale() as u32);
+    let (seconds, fraction) = (time.increments() / scale, time.increments() % scale);
+    match time.scale() {
+        0 => clock(seconds),
+        digits => format!("{}.{:0width$}", clock(seconds), fraction, width = digits as usize),
+    }
+}
+
+// Run a query and collect every row
+async fn query_table(conn: &mut DbConn, query: &str, params: &[Option<&str>]) -> Result<TableData, AppError> {
+    let mut rows = Vec::new();
+    let columns = for_each_row(conn, query, params, |row| {
+        rows.push(row);
+        Ok(())
+    }).await?;
+    Ok(TableData { columns, rows, ..Default::default() })
+}
+
+// Work done with a pooled connection; boxed so it can hold the connection across awaits
+type DbFuture<'c, T> = std::pin::Pin<Box<dyn std::future::Future<Output = Result<T, AppError>> + Send + 'c>>;
+
+// Run `work` on a pooled connection to `db_name`
+async fn with_connection<T>(
+    db_name: &str,
+    work: impl for<'c> FnOnce(&'c mut DbConn) -> DbFuture<'c, T> + Send,
+) -> Result<T, AppError> {
+    let pool = get_or_create_pool(db_name)?;
+    let started = Instant::now();
+    let mut conn = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            // A pool that never connected, e.g. for a database name nobody has, is
+            // dropped so it does not linger in /metrics and the pool list
+            if pool.pool.state().connections == 0 {
+                POOL_CACHE.remove_if(&pool_key(db_name), |_, entry| Arc::ptr_eq(entry, &pool));
+            }
+            return Err(e);
+        }
+    };
+    // Only databases that handed out a connection get a series, under the pool's name
+    METRICS.observe(&METRICS.pool_wait_seconds, pool_key(db_name), started.elapsed());
+    let result = work(&mut conn).await;
+    // Lost the server mid-query: don't let the pool hand this connection out again
+    if result.as_ref().is_err_and(|e| e.kind == ErrorKind::Unavailable) {
+        conn.mark_broken();
+    }
+    result
+}
+
+// The text to bind for a cell a client sent: JSON null writes NULL, and numbers
+// and booleans are written as their text rather than as an empty string
+fn cell_param(value: &serde_json::Value) -> Result<Option<String>, AppError> {
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(s) => Ok(Some(s.clone())),
+        serde_json::Value::Number(n) => Ok(Some(n.to_string())),
+        serde_json::Value::Bool(b) => Ok(Some(if *b { "1" } else { "0" }.to_string())),
+        _ => Err(AppError::validation("Cell values must be text, a number, a boolean or null")),
+    }
+}
+
+// Run an UPDATE or DELETE and return how many rows it touched
+async fn execute_counted(conn: &mut DbConn, sql: &str, params: &[Option<&str>]) -> Result<usize, AppError> {
+    let started = Instant::now();
+    let result = match conn {
+        #[cfg(feature = "odbc")]
+        DbConn::Odbc(conn) => tokio::task::block_in_place(|| {
+            let params: Vec<_> = params.iter().map(|p| p.into_parameter()).collect();
+            let mut statement = conn.preallocate()?;
+            statement.execute(sql, params.as_slice())?;
+            Ok(statement.row_count()?.unwrap_or(0))
+        }),
+        #[cfg(feature = "tiberius")]
+        DbConn::Tiberius(client) => {
+            let params: Vec<&dyn tiberius::ToSql> = params.iter().map(|p| p as &dyn tiberius::ToSql).collect();
+            let result = client.execute(tiberius_sql(sql), &params).await?;
+            Ok(result.total() as usize)
+        }
+    };
+    query_done(sql, started.elapsed());
+    result
+}
+
+// Start a transaction; every write until commit or rollback is held in it
+async fn begin_transaction(conn: &mut DbConn) -> Result<(), AppError> {
+    match conn {
+        #[cfg(feature = "odbc")]
+        DbConn::Odbc(conn) => tokio::task::block_in_place(|| conn.set_autocommit(false)).map_err(AppError::from),
+        #[cfg(feature = "tiberius")]
+        DbConn::Tiberius(client) => client.simple_query("BEGIN TRANSACTION").await?.into_results().await.map(|_| ()).map_err(AppError::from),
     }
 }
 
-// Helper to get or create a pool for a given database
-async fn get_or_create_pool(db_name: &str) -> Arc<Pool<OdbcManager>> {
-    if let Some(pool) = POOL_CACHE.get(db_name) {
-        return pool.clone();
+// Commit or roll back, leaving the connection in autocommit mode either way
+async fn end_transaction(conn: &mut DbConn, commit: bool) -> Result<(), AppError> {
+    match conn {
+        #[cfg(feature = "odbc")]
+        DbConn::Odbc(conn) => tokio::task::block_in_place(|| {
+            let finish = if commit { conn.commit() } else { conn.rollback() };
+            let restore = conn.set_autocommit(true);
+            finish?;
+            restore?;
+            Ok(())
+        }),
+        #[cfg(feature = "tiberius")]
+        DbConn::Tiberius(client) => {
+            let sql = if commit { "COMMIT TRANSACTION" } else { "IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION" };
+            client.simple_query(sql).await?.into_results().await.map(|_| ()).map_err(AppError::from)
+        }
     }
-    let conn_str = format!("Driver={{ODBC Driver 17 for SQL Server}};Server=mjm-sql01;Database={};Trusted_Connection=Yes;", db_name);
-    let manager = OdbcManager { conn_str };
-    let pool = Pool::builder().max_size(16).build(manager).await.expect("Failed to build pool");
-    let pool = Arc::new(pool);
-    POOL_CACHE.insert(db_name.to_string(), pool.clone());
-    pool
+}
+
+fn missing_row(table: &str, key_column: &str, key: &str) -> AppError {
+    AppError::not_found(format!("No {} row with {} = {}", table, key_column, key))
+        .with_details(serde_json::json!({ "table": table, "key_column": key_column, "key": key }))
+}
+
+// Insert one row and read it back, so generated IDs and defaults reach the client.
+// The row is found by the table's identity column via SCOPE_IDENTITY(), or by the key
+// supplied in the values. Both statements run in one transaction that is rolled back
+// unless exactly one row comes back, so a failed read never leaves a committed insert
+// behind for a client retry to duplicate. OUTPUT INSERTED.* is not used because it is
+// rejected on tables with triggers, such as the audit triggers reading SESSION_CONTEXT.
+async fn insert_returning(conn: &mut DbConn, table: &str, key_column: &str, columns: &[String], values: &[Option<String>]) -> Result<serde_json::Map<String, serde_json::Value>, AppError> {
+    let meta = table_meta(conn, table).await?;
+    let quoted = columns.iter()
+        .map(|c| meta.writable_column(c).map(|c| format!("[{}]", c.name)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let placeholders = vec!["?"; columns.len()].join(", ");
+    let mut params: Vec<Option<&str>> = values.iter().map(|v| v.as_deref()).collect();
+    let lookup = match (meta.column_info.iter().find(|c| c.identity), columns.iter().position(|c| c.eq_ignore_ascii_case(key_column))) {
+        (Some(identity), _) => format!("[{}] = SCOPE_IDENTITY()", identity.name),
+        (None, Some(i)) if values[i].is_some() => {
+            params.push(values[i].as_deref());
+            format!("[{}] = ?", meta.column(key_column)?.name)
+        }
+        (None, _) => return Err(AppError::validation(format!("{} is required to add a {} row", key_column, table))),
+    };
+    // NOCOUNT keeps the INSERT from producing a result ahead of the SELECT
+    let sql = format!(
+        "SET NOCOUNT ON; INSERT INTO [{table}] ({}) VALUES ({placeholders}); SET NOCOUNT OFF; SELECT * FROM [{table}] WHERE {lookup}",
+        quoted.join(", "),
+    );
+    begin_transaction(conn).await?;
+    let created = match query_table(conn, &sql, &params).await {
+        Ok(created) if created.rows.len() == 1 => created,
+        Ok(created) => {
+            end_transaction(conn, false).await?;
+            return Err(if created.rows.is_empty() {
+                AppError::internal(format!("Inserted {table} row could not be read back; the insert was rolled back"))
+            } else {
+                AppError::conflict(format!("{table} already has a row with this {key_column}; the insert was rolled back"))
+            });
+        }
+        Err(e) => {
+            end_transaction(conn, false).await?;
+            return Err(e);
+        }
+    };
+    end_transaction(conn, true).await?;
+    Ok(row_object(&created, &created.rows[0]))
+}
+
+fn row_object(table: &TableData, row: &[serde_json::Value]) -> serde_json::Map<String, serde_json::Value> {
+    table.columns.iter().zip(row).map(|(c, v)| (c.clone(), v.clone())).collect()
+}
+
+// Runtime settings, read once from the environment
+struct AppConfig {
+    bind_addr: SocketAddr,
+    data_dir: std::path::PathBuf,
+    session_hours: u64,
+    cookie_secure: bool,
+    login_max_failures: u32,
+    login_lockout_minutes: u64,
+    ldap_url: Option<String>,
+    ldap_bind_dn: String,
+    browse_tables: Vec<String>,
+    reference_mode: ReferenceMode,
+    job_workers: usize,
+    job_retention_hours: u64,
+    backend: Backend,
+    #[cfg(feature = "odbc")]
+    server: String,
+    #[cfg(feature = "odbc")]
+    odbc_driver: String,
+    #[cfg(feature = "tiberius")]
+    tiberius_connection: String,
+    pool_size: u32,
+    max_connections: usize,
+    pool_idle_minutes: u64,
+    metrics_token: Option<String>,
+    log_level: String,
+    log_dir: std::path::PathBuf,
+    log_keep_days: usize,
+    ready_databases: Vec<String>,
+}
+
+static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
+    let env = |key: &str| std::env::var(key).ok().filter(|v| !v.trim().is_empty());
+    let data_dir: std::path::PathBuf = env("DATABASE_DATA_DIR").unwrap_or_else(|| "data".to_string()).into();
+    let server = env("DATABASE_SERVER").unwrap_or_else(|| "localhost".to_string());
+    AppConfig {
+        bind_addr: env("DATABASE_BIND")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000))),
+        log_dir: env("DATABASE_LOG_DIR").map(Into::into).unwrap_or_else(|| data_dir.join("logs")),
+        data_dir,
+        session_hours: env("DATABASE_SESSION_HOURS").and_then(|v| v.parse().ok()).unwrap_or(12),
+        cookie_secure: env("DATABASE_COOKIE_SECURE").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+        // Failed sign-ins per username before it is refused for the lockout period
+        login_max_failures: env("DATABASE_LOGIN_MAX_FAILURES").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(5),
+        login_lockout_minutes: env("DATABASE_LOGIN_LOCKOUT_MINUTES").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(15),
+        ldap_url: env("DATABASE_LDAP_URL"),
+        // e.g. "{username}@corp.local" for Active Directory or "uid={username},ou=people,dc=example,dc=org"
+        ldap_bind_dn: env("DATABASE_LDAP_BIND_DN").unwrap_or_else(|| "{username}".to_string()),
+        // Tables the generic table browser may open
+        browse_tables: env("DATABASE_BROWSE_TABLES")
+            .unwrap_or_else(|| "Areaslevel3,Areaslevel2,Areaslevel1,Room_Schedule,Item_Schedule,Room_Types,Item_descriptions,ERM".to_string())
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect(),
+        reference_mode: match env("DATABASE_REFERENCE_MODE").as_deref() {
+            Some(v) if v.eq_ignore_ascii_case("strict") => ReferenceMode::Strict,
+            _ => ReferenceMode::Warn,
+        },
+        // Background jobs run at most this many at a time; the rest wait in the queue
+        job_workers: env("DATABASE_JOB_WORKERS").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(2),
+        job_retention_hours: env("DATABASE_JOB_RETENTION_HOURS").and_then(|v| v.parse().ok()).unwrap_or(24),
+        backend: match env("DATABASE_BACKEND").as_deref() {
+            Some(v) if v.eq_ignore_ascii_case("tiberius") => Backend::Tiberius,
+            Some(v) if v.eq_ignore_ascii_case("odbc") => Backend::Odbc,
+            // ODBC unless this build left it out
+            _ if cfg!(feature = "odbc") => Backend::Odbc,
+            _ => Backend::Tiberius,
+        },
+        // SQL Server host, or host\instance, for the ODBC backend and the default tiberius settings
+        #[cfg(feature = "odbc")]
+        server: server.clone(),
+        #[cfg(feature = "odbc")]
+        odbc_driver: env("DATABASE_ODBC_DRIVER").unwrap_or_else(|| "ODBC Driver 17 for SQL Server".to_string()),
+        // ADO.NET-style settings for the tiberius backend; the database is set per pool
+        // Credentials must be added; startup refuses the tiberius backend without them
+        #[cfg(feature = "tiberius")]
+        tiberius_connection: env("DATABASE_TIBERIUS_CONNECTION")
+            .unwrap_or_else(|| format!("server=tcp:{server};TrustServerCertificate=true")),
+        // Connections per database, and across every database together
+        pool_size: env("DATABASE_POOL_SIZE").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(16),
+        max_connections: env("DATABASE_MAX_CONNECTIONS").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(64),
+        // Idle connections are closed after this, and pools of databases nobody has used
+        pool_idle_minutes: env("DATABASE_POOL_IDLE_MINUTES").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(10),
+        // Bearer token a Prometheus scraper must send to /metrics
+        metrics_token: env("DATABASE_METRICS_TOKEN"),
+        // A tracing filter, e.g. "debug" or "info,sql=warn" to leave out the per-query lines
+        log_level: env("DATABASE_LOG").unwrap_or_else(|| "info".to_string()),
+        log_keep_days: env("DATABASE_LOG_KEEP_DAYS").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(14),
+        // Project databases /readyz checks besides master
+        ready_databases: env("DATABASE_READY_DATABASES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty() && !d.eq_ignore_ascii_case("master"))
+            .fold(Vec::new(), |mut dbs: Vec<String>, d| {
+                if !dbs.iter().any(|x| x.eq_ignore_ascii_case(&d)) {
+                    dbs.push(d);
+                }
+                dbs
+            }),
+    }
+});
+
+// Small JSON files under the data directory hold server-side state (accounts etc.)
+fn load_store<T: serde::de::DeserializeOwned + Default>(name: &str) -> T {
+    let path = CONFIG.data_dir.join(name);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring unreadable {}: {e}", path.display());
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+fn save_store<T: Serialize>(name: &str, value: &T) -> Result<(), String> {
+    let path = CONFIG.data_dir.join(name);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let text = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    // Write then rename so a crash never leaves a half-written file behind
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, text).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, &path).map_err(|e| e.to_string())
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Readable lines on the console, and JSON lines with the request span in daily
+// files under DATABASE_LOG_DIR. Keep the guard alive so buffered lines get written.
+fn init_logging() -> Option<tracing_appender::non_blocking::WorkerGuard> {
+    use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+    let (filter, filter_error) = match EnvFilter::try_new(&CONFIG.log_level) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e)),
+    };
+    let appender = tracing_appender::rolling::RollingFileAppender::builder()
+        .rotation(tracing_appender::rolling::Rotation::DAILY)
+        .filename_prefix("database")
+        .filename_suffix("log")
+        .max_log_files(CONFIG.log_keep_days)
+        .build(&CONFIG.log_dir);
+    let (file_layer, guard, file_error) = match appender {
+        Ok(appender) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(writer);
+            (Some(layer), Some(guard), None)
+        }
+        Err(e) => (None, None, Some(e)),
+    };
+    tracing_subscriber::registry().with(filter).with(fmt::layer()).with(file_layer).init();
+    if let Some(e) = filter_error {
+        tracing::warn!("Ignoring invalid DATABASE_LOG: {e}");
+    }
+    if let Some(e) = file_error {
+        tracing::warn!("Logging to the console only, {} is not usable: {e}", CONFIG.log_dir.display());
+    }
+    guard
 }
 
 #[tokio::main]
 async fn main() {
-    // Set up ODBC connection manager and pool
-    let conn_str = "Driver={ODBC Driver 17 for SQL Server};Server=mjm-sql01;Trusted_Connection=Yes;";
-    let manager = OdbcManager { conn_str: conn_str.to_string() };
-    let pool = Pool::builder().max_size(16).build(manager).await.expect("Failed to build pool");
-    let pool = Arc::new(pool);
-    POOL_CACHE.insert("master".to_string(), pool.clone()); // Add master pool to cache
+    let _log_guard = init_logging();
+    Lazy::force(&STARTED);
+    #[cfg(feature = "tiberius")]
+    if let (Backend::Tiberius, Err(e)) = (CONFIG.backend, tiberius_config()) {
+        tracing::error!("Invalid DATABASE_TIBERIUS_CONNECTION: {e}");
+        std::process::exit(1);
+    }
+    // Set up the master pool ahead of the first request
+    let pool = match get_or_create_pool("master") {
+        Ok(pool) => pool,
+        Err(e) => {
+            tracing::error!("Could not set up the master pool: {}", e.message);
+            std::process::exit(1);
+        }
+    };
+    bootstrap_admin_account().await;
+    recover_jobs().await;
+    // Close the pools of databases nobody has used for a while, and change feeds nobody listens to
+    tokio::spawn(async {
+        let mut tick = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tick.tick().await;
+            evict_idle_pools(CONFIG.pool_idle_minutes * 60);
+            drop_idle_feeds();
+        }
+    });
+    tokio::spawn(async {
+        let mut tick = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tick.tick().await;
+            sweep_sessions();
+        }
+    });
 
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/api/databases", get(get_databases))
+    let db_routes = Router::new()
         .route("/db/:db_name", get(db_control_space))
         .route("/api/db/:db_name/areaslevel3", get(get_areaslevel3))
         .route("/api/db/:db_name/areaslevel3/update", axum::routing::post(update_areaslevel3_cell))
+        .route("/api/db/:db_name/areaslevel3/update_batch", axum::routing::post(batch_update_areaslevel3))
         .route("/api/db/:db_name/areaslevel3/add", axum::routing::post(add_areaslevel3_row))
         .route("/api/db/:db_name/areaslevel3/delete", axum::routing::post(delete_areaslevel3_row))
         .route("/db/:db_name/areaslevel2/:parent_id", get(areaslevel2_page))
         .route("/api/db/:db_name/areaslevel2/:parent_id", get(get_areaslevel2))
         .route("/api/db/:db_name/areaslevel2/update", axum::routing::post(update_areaslevel2_cell))
+        .route("/api/db/:db_name/areaslevel2/update_batch", axum::routing::post(batch_update_areaslevel2))
         .route("/api/db/:db_name/areaslevel2/add", axum::routing::post(add_areaslevel2_row))
         .route("/api/db/:db_name/areaslevel2/delete", axum::routing::post(delete_areaslevel2_row))
         .route("/db/:db_name/areaslevel1/:parent_id", get(areaslevel1_page))
         .route("/api/db/:db_name/areaslevel1/:parent_id", get(get_areaslevel1))
         .route("/api/db/:db_name/areaslevel1/update", axum::routing::post(update_areaslevel1_cell))
+        .route("/api/db/:db_name/areaslevel1/update_batch", axum::routing::post(batch_update_areaslevel1))
         .route("/api/db/:db_name/areaslevel1/add", axum::routing::post(add_areaslevel1_row))
         .route("/api/db/:db_name/areaslevel1/delete", axum::routing::post(delete_areaslevel1_row))
         .route("/db/:db_name/room_schedule/:parent_id", get(room_schedule_page))
         .route("/api/db/:db_name/room_schedule/:parent_id", get(get_room_schedule))
         .route("/api/db/:db_name/room_schedule/update", axum::routing::post(update_room_schedule_cell))
+        .route("/api/db/:db_name/room_schedule/update_batch", axum::routing::post(batch_update_room_schedule))
         .route("/api/db/:db_name/room_schedule/add", axum::routing::post(add_room_schedule_row))
         .route("/api/db/:db_name/room_schedule/delete", axum::routing::post(delete_room_schedule_row))
         .route("/db/:db_name/room_schedule_all", get(room_schedule_all_page))
@@ -1442,25 +2997,256 @@ async fn main() {
         .route("/db/:db_name/item_schedule/:room_code", get(item_schedule_page))
         .route("/api/db/:db_name/item_schedule/:room_code", get(get_item_schedule))
         .route("/api/db/:db_name/item_schedule/update", axum::routing::post(update_item_schedule_cell))
+        .route("/api/db/:db_name/item_schedule/update_batch", axum::routing::post(batch_update_item_schedule))
         .route("/api/db/:db_name/item_schedule/add", axum::routing::post(add_item_schedule_row))
         .route("/api/db/:db_name/item_schedule/delete", axum::routing::post(delete_item_schedule_row))
+        .route("/db/:db_name/room_items/:room_id", get(room_items_page))
+        .route("/api/db/:db_name/room_items/:room_id", get(get_room_items))
         .route("/db/:db_name/item_descriptions_search", get(item_descriptions_search_page))
         .route("/api/db/:db_name/item_descriptions", get(get_item_descriptions))
         .route("/db/:db_name/room_types_search", axum::routing::get(room_types_search_page))
         .route("/api/db/:db_name/room_types", axum::routing::get(get_room_types))
         .route("/api/db/:db_name/room_types/update", axum::routing::post(update_room_type_cell))
+        .route("/api/db/:db_name/room_types/update_batch", axum::routing::post(batch_update_room_types))
         .route("/api/db/:db_name/item_descriptions/update", axum::routing::post(update_item_description_cell))
+        .route("/api/db/:db_name/item_descriptions/update_batch", axum::routing::post(batch_update_item_descriptions))
         .route("/api/db/:db_name/item_descriptions/update_erm", axum::routing::post(update_erm_cell))
+        .route("/api/db/:db_name/item_descriptions/update_erm_batch", axum::routing::post(batch_update_erm))
+        .route("/db/:db_name/table/:table", get(browse_page))
+        .route("/api/db/:db_name/table", get(list_browse_tables))
+        .route("/api/db/:db_name/table/:table", get(get_browse_table))
+        .route("/api/db/:db_name/table/:table/add", axum::routing::post(add_browse_row))
+        .route("/api/db/:db_name/table/:table/update", axum::routing::post(update_browse_cell))
+        .route("/api/db/:db_name/table/:table/delete", axum::routing::post(delete_browse_row))
+        .route("/db/:db_name/integrity", get(integrity_page))
+        .route("/api/db/:db_name/integrity", get(integrity_report))
+        .route("/api/db/:db_name/integrity/references", get(reference_report))
+        .route("/api/db/:db_name/integrity/fix", axum::routing::post(apply_integrity_fix))
+        .route("/db/:db_name/reports/equipment", get(equipment_report_page))
+        .route("/api/db/:db_name/reports/equipment", get(equipment_report))
+        .route("/db/:db_name/reports/accommodation", get(accommodation_page))
+        .route("/db/:db_name/rollup_rules", get(rollup_rules_page))
+        .route("/api/db/:db_name/rollup_rules", get(get_rollup_rules))
+        .route("/api/db/:db_name/rollup_rules/update", axum::routing::post(update_rollup_rules))
+        .route("/api/db/:db_name/reports/accommodation", get(get_accommodation_report))
+        .route("/api/db/:db_name/room_sheets/room/:room_id", get(room_sheet))
+        .route("/api/db/:db_name/room_sheets/areaslevel1/:arealevel_id", get(areaslevel1_room_sheets))
+        .route("/api/db/:db_name/room_sheets/areaslevel2/:arealevel_id", get(areaslevel2_room_sheets))
+        .route("/api/db/:db_name/lock", get(get_lock_status))
+        .route("/api/db/:db_name/changes", get(change_feed))
+        .route("/api/db/:db_name/editing", axum::routing::post(announce_editing))
+        .route("/api/db/:db_name/snapshots", get(list_snapshots))
+        .route("/api/db/:db_name/snapshots/create", axum::routing::post(create_snapshot))
+        .route("/api/db/:db_name/snapshots/delete", axum::routing::post(delete_snapshot))
+        .route("/api/db/:db_name/snapshots/:name/diff", get(diff_snapshot))
+        .route("/db/:db_name/jobs", get(jobs_page))
+        .route("/api/db/:db_name/jobs/start", axum::routing::post(start_job))
+        .route_layer(axum::middleware::from_fn(reject_if_locked))
+        .route_layer(axum::middleware::from_fn(require_permission));
+
+    let admin_routes = Router::new()
+        .route("/api/admin/users", get(admin_list_users))
+        .route("/api/admin/users/add", axum::routing::post(admin_add_user))
+        .route("/api/admin/users/delete", axum::routing::post(admin_delete_user))
+        .route("/api/admin/grants", get(admin_list_grants))
+        .route("/api/admin/grants/add", axum::routing::post(admin_add_grant))
+        .route("/api/admin/grants/delete", axum::routing::post(admin_delete_grant))
+        .route("/api/admin/locks", get(admin_list_locks))
+        .route("/api/admin/locks/lock", axum::routing::post(admin_lock_database))
+        .route("/api/admin/locks/unlock", axum::routing::post(admin_unlock_database))
+        .route("/api/admin/pools", get(admin_list_pools))
+        .route_layer(axum::middleware::from_fn(require_admin));
+
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/api/databases", get(get_databases))
+        .merge(db_routes)
+        .merge(admin_routes)
+        .route("/api/me", get(get_current_user))
+        .route("/api/me/password", axum::routing::post(change_password))
+        .route("/api/logout", axum::routing::post(logout))
+        .route("/api/jobs", get(list_jobs))
+        .route("/api/jobs/:job_id", get(get_job))
+        .route("/api/jobs/:job_id/result", get(download_job_result))
+        .route("/api/jobs/:job_id/cancel", axum::routing::post(cancel_job))
+        .route_layer(axum::middleware::from_fn(require_login))
+        .route("/login", get(login_page))
+        .route("/api/login", axum::routing::post(login))
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(axum::middleware::from_fn(track_requests))
         .layer(Extension(pool));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    println!("Listening on {}", addr);
+    let addr = CONFIG.bind_addr;
+    tracing::info!("Listening on {}", addr);
     let listener = TcpListener::bind(addr).await.unwrap();
 
-    // Open browser BEFORE serving
-    if webbrowser::open("http://127.0.0.1:3000").is_ok() {
-        println!("Opened browser to http://127.0.0.1:3000");
+    // Open browser BEFORE serving (only when running locally)
+    if addr.ip().is_loopback() {
+        let url = format!("http://{}", addr);
+        if webbrowser::open(&url).is_ok() {
+            tracing::info!("Opened browser to {}", url);
+        }
     }
 
-    axum::serve(listener, app).await.unwrap();
+    // Client addresses feed the sign-in throttle
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table(columns: &[&str], rows: Vec<Vec<serde_json::Value>>) -> TableData {
+        TableData { columns: columns.iter().map(|c| c.to_string()).collect(), rows, ..Default::default() }
+    }
+
+    #[test]
+    fn row_version_is_stable_and_sees_every_cell() {
+        let token = row_version(["12", "A1", "Ward"]);
+        assert_eq!(token, row_version(["12", "A1", "Ward"]));
+        assert_eq!(token.len(), 16);
+        assert_ne!(token, row_version(["12", "A1", "Ward 2"]));
+        assert_ne!(token, row_version(["12", "A1"]));
+        // Cells are separated, so moving text across a boundary is a change
+        assert_ne!(row_version(["ab", "c"]), row_version(["a", "bc"]));
+    }
+
+    #[test]
+    fn page_versions_match_the_row_read_back_for_conflicts() {
+        // Pages may order columns differently from the versioned SELECT
+        let page = table(
+            &["AreaDescription", "ArealevelID", "ParentArea", "Area"],
+            vec![vec![json!("Ward"), json!(12), json!("A1"), json!(40.5)]],
+        ).with_versions(&AREASLEVEL3_VERSION);
+        let selected = [json!(12), json!("A1"), json!("Ward")];
+        assert_eq!(page.versions[0], row_version(selected.iter().map(cell_text)));
+        let edited = [json!(12), json!("A1"), json!("Ward (old)")];
+        assert_ne!(page.versions[0], row_version(edited.iter().map(cell_text)));
+    }
+
+    #[test]
+    fn rollup_rules_round_by_mode_and_precision() {
+        let rules = |rounding, precision| RollupRules { rounding, precision, ..Default::default() };
+        assert_eq!(RollupRules::default().round(12.9), 12.0);
+        assert_eq!(rules(RoundingMode::Ceil, 1).round(12.01), 12.1);
+        assert_eq!(rules(RoundingMode::Nearest, 2).round(2.346), 2.35);
+        assert_eq!(rules(RoundingMode::Nearest, 0).round(2.5), 3.0);
+        assert_eq!(rules(RoundingMode::None, 0).round(2.346), 2.346);
+    }
+
+    #[test]
+    fn rollup_rules_format_with_the_kept_places() {
+        let rules = |rounding, precision| RollupRules { rounding, precision, ..Default::default() };
+        assert_eq!(RollupRules::default().format(123.9), "123");
+        assert_eq!(rules(RoundingMode::Ceil, 1).format(2.01), "2.1");
+        assert_eq!(rules(RoundingMode::Floor, 2).format(2.0), "2.00");
+        assert_eq!(rules(RoundingMode::None, 4).format(1.5), "1.50");
+        assert_eq!(rules(RoundingMode::None, 0).value(1.234), json!(1.23));
+    }
+
+    #[test]
+    fn area_pages_total_the_same_as_the_report() {
+        let areas = |rows: &[[&str; 3]]| table(&["ArealevelID", "ParentArea", "AreaDescription"], rows.iter().map(|r| r.iter().map(|c| json!(c)).collect()).collect());
+        let project = AreaTables {
+            a3: areas(&[["P1", "", "Site"]]),
+            a2: areas(&[["B1", "P1", "Block"], ["B2", "P1", "Annex"]]),
+            a1: areas(&[["W1", "B1", "Ward"], ["W2", "B1", "Theatre"], ["W3", "B2", "Store"]]),
+            // Spacing, exponents, a thousands separator and mixed flag spellings
+            rooms: table(&["ParentArea", "Area", "Ignore_Flag"], vec![
+                vec![json!("W1"), json!(" 10.5 "), json!("N")],
+                vec![json!("w1 "), json!("1e1"), json!("no")],
+                vec![json!("W2"), json!("1,234"), json!(" Yes")],
+                vec![json!("W2"), json!(4), json!(0)],
+                vec![json!("W3"), json!(7.25), json!("")],
+            ]),
+        };
+        let rules = RollupRules { exclude_ignored: true, rounding: RoundingMode::Nearest, precision: 2, uplift: LevelUplift { level1: 10.0, level2: 0.0, level3: 5.0 } };
+        let report = project.rollup(&rules);
+        // What AreaLevelTables::load reads for one page
+        let page = |level: usize, parent: Option<&str>| {
+            let all = [&project.a3, &project.a2, &project.a1];
+            let mut levels = all.map(|t| TableData { rows: Vec::new(), ..t.clone() });
+            let mut ids: Option<Vec<String>> = parent.map(|p| vec![p.to_string()]);
+            let under = |t: &TableData, r: &[serde_json::Value], ids: &Option<Vec<String>>| {
+                ids.as_ref().is_none_or(|ids| ids.iter().any(|id| same_key(&t.cell(r, "ParentArea"), id)))
+            };
+            for l in (1..=level).rev() {
+                let t = all[3 - l];
+                levels[3 - l].rows = t.rows.iter().filter(|r| under(t, r, &ids)).cloned().collect();
+                ids = Some(levels[3 - l].rows.iter().map(|r| t.cell(r, "ArealevelID").into_owned()).collect());
+            }
+            let rooms = &project.rooms;
+            AreaLevelTables { levels, rooms: TableData { rows: rooms.rows.iter().filter(|r| under(rooms, r, &ids)).cloned().collect(), ..rooms.clone() } }
+        };
+        for (level, parent) in [(3, None), (2, Some("P1")), (1, Some("B1")), (1, Some("B2"))] {
+            let rows = page(level, parent).rollup(&rules).area_rows(level, parent);
+            assert!(!rows.rows.is_empty());
+            for row in &rows.rows {
+                let total = report.total(level, &rows.cell(row, "ArealevelID"));
+                assert_eq!(row[3], rules.value(total.area), "level {level} {}", rows.cell(row, "ArealevelID"));
+                assert_eq!(row[4], rules.value(total.gross));
+            }
+        }
+        assert_eq!(report.total(1, "W1").area, 20.5);
+    }
+
+    #[test]
+    fn driver_errors_are_classified_by_sqlstate() {
+        assert_eq!(classify_driver_error("23000", "2627").0, ErrorKind::Conflict);
+        assert_eq!(classify_driver_error("23000", "547").0, ErrorKind::Conflict);
+        assert_eq!(classify_driver_error("23000", "515").0, ErrorKind::Validation);
+        assert_eq!(classify_driver_error("22001", "8152").0, ErrorKind::Validation);
+        assert_eq!(classify_driver_error("08S01", "").0, ErrorKind::Unavailable);
+        assert_eq!(classify_driver_error("HYT00", "").0, ErrorKind::Unavailable);
+        assert_eq!(classify_driver_error("42S02", "208").0, ErrorKind::Internal);
+    }
+
+    #[cfg(feature = "tiberius")]
+    #[test]
+    fn tiberius_sql_numbers_placeholders_outside_quotes() {
+        assert_eq!(
+            tiberius_sql("SELECT * FROM t WHERE [a] = ? AND b = '?' AND [c?] = ? AND \"d?\" = ?"),
+            "SELECT * FROM t WHERE [a] = @P1 AND b = '?' AND [c?] = @P2 AND \"d?\" = @P3",
+        );
+    }
+
+    #[cfg(feature = "tiberius")]
+    #[test]
+    fn sql_date_counts_from_year_one() {
+        assert_eq!(sql_date(0), "0001-01-01");
+        assert_eq!(sql_date(DAYS_TO_1900), "1900-01-01");
+        assert_eq!(sql_date(730_178), "2000-02-29");
+        assert_eq!(sql_date(739_250), "2024-12-31");
+    }
+
+    #[test]
+    fn integrity_fixes_need_the_access_of_their_table() {
+        let route = route_access("/api/db/:db_name/integrity/fix", None).unwrap();
+        let allowed = |role, check, fix| {
+            let (_, fix) = integrity_fix(check, fix).unwrap();
+            role_allows(role, route) && role_allows(role, fix.access)
+        };
+        assert!(allowed(Role::Librarian, "items_without_erm", "create_erm_entries"));
+        assert!(!allowed(Role::Planner, "items_without_erm", "create_erm_entries"));
+        assert!(allowed(Role::Planner, "negative_quantities", "zero_negative_quantities"));
+        assert!(!allowed(Role::Librarian, "negative_quantities", "zero_negative_quantities"));
+        assert!(!allowed(Role::Librarian, "items_without_room", "delete_items_without_room"));
+        assert!(!allowed(Role::Viewer, "items_without_erm", "create_erm_entries"));
+        assert!(integrity_fix("items_without_erm", "zero_negative_quantities").is_none());
+    }
+
+    #[test]
+    fn rooms_without_a_numeric_id_get_their_type_list() {
+        let (sql, params) = room_items_query("R1", "BED1", false);
+        assert_eq!(params, ["BED1"]);
+        assert!(sql.contains("ISNULL(s.[instance_variant], 0) = 0 ORDER BY"));
+        assert!(!sql.contains("o.[instance_variant] = ?"));
+        assert_eq!(room_items_query("0", "BED1", false).1, ["BED1"]);
+        let (sql, params) = room_items_query(" 12 ", "BED1", false);
+        assert_eq!(params, ["BED1", "12", "12"]);
+        assert!(sql.contains("o.[instance_variant] = ?"));
+    }
 }
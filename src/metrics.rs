@@ -0,0 +1,247 @@
+// Health probes and Prometheus metrics
+
+use super::*;
+
+pub(crate) static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+// A probe gives up on a database after this long rather than waiting out the pool timeout
+pub(crate) const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Liveness: answers as long as the process is serving requests
+pub(crate) async fn healthz() -> axum::response::Response {
+    Json(serde_json::json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": STARTED.elapsed().as_secs(),
+    })).into_response()
+}
+
+#[derive(Serialize)]
+pub(crate) struct ComponentStatus {
+    // "up" or "down"
+    pub(crate) status: &'static str,
+    pub(crate) elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+// Run SELECT 1 on a pooled connection to `db_name`
+pub(crate) async fn check_database(db_name: String) -> (String, ComponentStatus) {
+    let started = Instant::now();
+    let probe = with_connection(&db_name, |conn| Box::pin(async move {
+        query_table(conn, "SELECT 1", &[]).await.map(|_| ())
+    }));
+    let error = match tokio::time::timeout(READY_TIMEOUT, probe).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.message),
+        Err(_) => Some(format!("No answer within {} seconds", READY_TIMEOUT.as_secs())),
+    };
+    let status = ComponentStatus {
+        status: if error.is_none() { "up" } else { "down" },
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        error,
+    };
+    (db_name, status)
+}
+
+// Readiness: the master database, and any in DATABASE_READY_DATABASES, answer
+// SELECT 1. 503 when any of them does not, so a proxy or service manager can act.
+pub(crate) async fn readyz() -> axum::response::Response {
+    let mut checks = tokio::task::JoinSet::new();
+    for db_name in std::iter::once("master".to_string()).chain(CONFIG.ready_databases.iter().cloned()) {
+        checks.spawn(check_database(db_name));
+    }
+    let expected = checks.len();
+    let mut components = std::collections::BTreeMap::new();
+    while let Some(done) = checks.join_next().await {
+        match done {
+            Ok((db_name, status)) => {
+                components.insert(db_name, status);
+            }
+            Err(e) => tracing::error!("Readiness check failed: {e}"),
+        }
+    }
+    let ready = components.len() == expected && components.values().all(|c| c.error.is_none());
+    let status = if ready { axum::http::StatusCode::OK } else { axum::http::StatusCode::SERVICE_UNAVAILABLE };
+    let body = serde_json::json!({ "status": if ready { "ready" } else { "unavailable" }, "components": components });
+    (status, Json(body)).into_response()
+}
+
+// Upper bounds, in seconds, of the latency histogram buckets
+pub(crate) const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Counts are kept cumulative per bucket, as Prometheus reads them
+#[derive(Default)]
+pub(crate) struct Histogram {
+    pub(crate) buckets: [u64; LATENCY_BUCKETS.len()],
+    pub(crate) sum: f64,
+    pub(crate) count: u64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    pub(crate) fn render(&self, out: &mut String, name: &str, labels: &str) {
+        use std::fmt::Write;
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+// Everything /metrics reports that is not read from the pools at scrape time
+#[derive(Default)]
+pub(crate) struct Metrics {
+    // (method, route pattern, status)
+    pub(crate) requests: DashMap<(String, String, u16), u64>,
+    // (method, route pattern)
+    pub(crate) request_seconds: DashMap<(String, String), Histogram>,
+    // By the table a statement touches, see sql_table
+    pub(crate) query_seconds: DashMap<String, Histogram>,
+    // By database: time spent waiting for a pooled connection
+    pub(crate) pool_wait_seconds: DashMap<String, Histogram>,
+    // (table, change kind) of every published change
+    pub(crate) mutations: DashMap<(String, String), u64>,
+    pub(crate) database_cache_hits: std::sync::atomic::AtomicU64,
+    pub(crate) database_cache_misses: std::sync::atomic::AtomicU64,
+}
+
+pub(crate) static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+impl Metrics {
+    pub(crate) fn observe<K: std::hash::Hash + Eq>(&self, map: &DashMap<K, Histogram>, key: K, elapsed: Duration) {
+        map.entry(key).or_default().observe(elapsed.as_secs_f64());
+    }
+}
+
+// Every statement is timed for /metrics and logged with its SQL under the "sql" target
+pub(crate) fn query_done(sql: &str, elapsed: Duration) {
+    let table = sql_table(sql);
+    tracing::info!(target: "sql", table = %table, elapsed_ms = elapsed.as_millis() as u64, sql, "query");
+    METRICS.observe(&METRICS.query_seconds, table, elapsed);
+}
+
+// The table a statement is about, for labelling: what follows its first FROM,
+// INTO or UPDATE, or the procedure an EXEC calls
+pub(crate) fn sql_table(sql: &str) -> String {
+    let mut words = sql.split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ',' | ';')).filter(|w| !w.is_empty());
+    while let Some(word) = words.next() {
+        if !["FROM", "INTO", "UPDATE", "EXEC"].iter().any(|k| word.eq_ignore_ascii_case(k)) {
+            continue;
+        }
+        // FROM (SELECT ...) names its table further on
+        match words.next() {
+            Some(name) if !name.eq_ignore_ascii_case("SELECT") => return name.replace(['[', ']'], ""),
+            _ => continue,
+        }
+    }
+    "other".to_string()
+}
+
+// A label value with Prometheus' escapes
+pub(crate) fn metric_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Middleware: count and time every request by its route pattern, so ids in the
+// path do not each become a series, and run it in a span that its logs share.
+// require_login fills in the span's user.
+pub(crate) async fn track_requests(params: Option<axum::extract::RawPathParams>, req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    use tracing::Instrument;
+    let method = req.method().to_string();
+    let route = req.extensions().get::<axum::extract::MatchedPath>().map(|m| m.as_str().to_string()).unwrap_or_else(|| "unmatched".to_string());
+    let db_name = params.and_then(|p| p.iter().find(|(key, _)| *key == "db_name").map(|(_, value)| value.to_string()));
+    let span = tracing::info_span!(
+        "request",
+        method = %method,
+        route = %route,
+        path = %req.uri().path(),
+        db_name = db_name.as_deref(),
+        user = tracing::field::Empty,
+    );
+    let started = Instant::now();
+    let response = next.run(req).instrument(span.clone()).await;
+    let elapsed = started.elapsed();
+    let status = response.status().as_u16();
+    span.in_scope(|| tracing::info!(status, elapsed_ms = elapsed.as_millis() as u64, "request"));
+    *METRICS.requests.entry((method.clone(), route.clone(), status)).or_default() += 1;
+    METRICS.observe(&METRICS.request_seconds, (method, route), elapsed);
+    response
+}
+
+// Prometheus text exposition. A scraper sends DATABASE_METRICS_TOKEN as a bearer
+// token; otherwise only a signed-in administrator may read it.
+pub(crate) async fn metrics(headers: axum::http::HeaderMap) -> axum::response::Response {
+    use std::fmt::Write;
+    use std::sync::atomic::Ordering;
+    let sent = headers.get(axum::http::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let token_ok = CONFIG.metrics_token.as_ref().is_some_and(|token| sent == Some(format!("Bearer {token}").as_str()));
+    if !token_ok {
+        match session_user(&headers) {
+            Some(user) if is_admin(&user.username).await => {}
+            Some(_) => return AppError::new(ErrorKind::Forbidden, "Administrator role required").into_response(),
+            None => return AppError::new(ErrorKind::Unauthorized, "Metrics token or sign-in required").into_response(),
+        }
+    }
+    let mut out = String::new();
+    let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    };
+
+    header(&mut out, "http_requests_total", "counter", "HTTP requests by route pattern and status.");
+    for entry in METRICS.requests.iter() {
+        let (method, route, status) = entry.key();
+        let _ = writeln!(out, "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, metric_label(route), status, entry.value());
+    }
+    header(&mut out, "http_request_duration_seconds", "histogram", "HTTP request latency by route pattern.");
+    for entry in METRICS.request_seconds.iter() {
+        let (method, route) = entry.key();
+        entry.value().render(&mut out, "http_request_duration_seconds", &format!("method=\"{}\",route=\"{}\"", method, metric_label(route)));
+    }
+    header(&mut out, "db_query_duration_seconds", "histogram", "SQL statement duration by table.");
+    for entry in METRICS.query_seconds.iter() {
+        entry.value().render(&mut out, "db_query_duration_seconds", &format!("table=\"{}\"", metric_label(entry.key())));
+    }
+    header(&mut out, "db_pool_wait_seconds", "histogram", "Time spent waiting for a pooled connection, by database.");
+    for entry in METRICS.pool_wait_seconds.iter() {
+        entry.value().render(&mut out, "db_pool_wait_seconds", &format!("database=\"{}\"", metric_label(entry.key())));
+    }
+    header(&mut out, "db_mutations_total", "counter", "Changes made through the app, by table and kind.");
+    for entry in METRICS.mutations.iter() {
+        let (table, kind) = entry.key();
+        let _ = writeln!(out, "db_mutations_total{{table=\"{}\",kind=\"{}\"}} {}", metric_label(table), metric_label(kind), entry.value());
+    }
+
+    header(&mut out, "db_pool_connections", "gauge", "Open connections in each database's pool.");
+    for entry in POOL_CACHE.iter() {
+        let _ = writeln!(out, "db_pool_connections{{database=\"{}\"}} {}", metric_label(entry.key()), entry.pool.state().connections);
+    }
+    header(&mut out, "db_pool_idle_connections", "gauge", "Idle connections in each database's pool.");
+    for entry in POOL_CACHE.iter() {
+        let _ = writeln!(out, "db_pool_idle_connections{{database=\"{}\"}} {}", metric_label(entry.key()), entry.pool.state().idle_connections);
+    }
+    header(&mut out, "db_pool_max_connections", "gauge", "Connections each database's pool may open.");
+    let _ = writeln!(out, "db_pool_max_connections {}", CONFIG.pool_size);
+    header(&mut out, "db_connections_open", "gauge", "Open connections across all pools.");
+    let _ = writeln!(out, "db_connections_open {}", CONFIG.max_connections - CONNECTION_SLOTS.available_permits());
+    header(&mut out, "db_connections_max", "gauge", "Open connections allowed across all pools.");
+    let _ = writeln!(out, "db_connections_max {}", CONFIG.max_connections);
+
+    header(&mut out, "database_list_cache_hits_total", "counter", "Database list requests answered from the cache.");
+    let _ = writeln!(out, "database_list_cache_hits_total {}", METRICS.database_cache_hits.load(Ordering::Relaxed));
+    header(&mut out, "database_list_cache_misses_total", "counter", "Database list requests that queried the server.");
+    let _ = writeln!(out, "database_list_cache_misses_total {}", METRICS.database_cache_misses.load(Ordering::Relaxed));
+
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
@@ -0,0 +1,343 @@
+// Connection pools per database over the ODBC or tiberius backend
+
+use super::*;
+
+pub(crate) static POOL_CACHE: once_cell::sync::Lazy<DashMap<String, Arc<PoolEntry>>> = once_cell::sync::Lazy::new(DashMap::new);
+
+// Open connections across every database's pool, from DATABASE_MAX_CONNECTIONS
+pub(crate) static CONNECTION_SLOTS: Lazy<Arc<tokio::sync::Semaphore>> = Lazy::new(|| Arc::new(tokio::sync::Semaphore::new(CONFIG.max_connections)));
+
+// A driver connection as the pools hold it: it keeps one of CONNECTION_SLOTS while
+// open, and is flagged broken once a query on it has lost the server
+pub(crate) struct Slotted<C> {
+    pub(crate) conn: C,
+    pub(crate) broken: bool,
+    pub(crate) _slot: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl<C> std::ops::Deref for Slotted<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.conn
+    }
+}
+
+impl<C> std::ops::DerefMut for Slotted<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+}
+
+// How long a request waits for a connection, from its pool or for a free slot
+pub(crate) const CONNECTION_WAIT: Duration = Duration::from_secs(30);
+
+// Wait for a free connection slot. When none is left, idle pools are closed and then
+// idle connections held by other pools, and the wait gives up after CONNECTION_WAIT
+// so a saturated server answers instead of queueing requests forever.
+pub(crate) async fn connection_slot() -> Result<tokio::sync::OwnedSemaphorePermit, String> {
+    if let Ok(slot) = CONNECTION_SLOTS.clone().try_acquire_owned() {
+        return Ok(slot);
+    }
+    evict_idle_pools(0);
+    trim_idle_connections().await;
+    match tokio::time::timeout(CONNECTION_WAIT, CONNECTION_SLOTS.clone().acquire_owned()).await {
+        Ok(slot) => slot.map_err(|e| e.to_string()),
+        Err(_) => Err(connection_limit_message()),
+    }
+}
+
+pub(crate) fn connection_limit_message() -> String {
+    format!("Connection limit reached: all {} connections allowed by DATABASE_MAX_CONNECTIONS are in use", CONFIG.max_connections)
+}
+
+// Close idle connections one at a time, from whichever pool holds the most, until a
+// slot frees up. bb8 cannot drop an idle connection directly, so each one is checked
+// out and marked broken, which makes the pool close it on return. Only one task trims
+// at a time; the others just wait for the slots it frees.
+pub(crate) async fn trim_idle_connections() {
+    static TRIMMING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+    if TRIMMING.swap(true, std::sync::atomic::Ordering::AcqRel) {
+        return;
+    }
+    while CONNECTION_SLOTS.available_permits() == 0 {
+        let fullest = POOL_CACHE.iter()
+            .map(|entry| entry.value().clone())
+            .filter(|entry| entry.pool.state().idle_connections > 0)
+            .max_by_key(|entry| entry.pool.state().idle_connections);
+        let Some(entry) = fullest else { break };
+        match tokio::time::timeout(Duration::from_secs(1), entry.pool.get()).await {
+            Ok(Ok(mut conn)) => conn.mark_broken(),
+            _ => break,
+        }
+    }
+    TRIMMING.store(false, std::sync::atomic::Ordering::Release);
+}
+
+// Custom ODBC connection manager for bb8
+#[cfg(feature = "odbc")]
+#[derive(Clone, Debug)]
+pub(crate) struct OdbcManager {
+    pub(crate) conn_str: String,
+}
+
+#[cfg(feature = "odbc")]
+#[async_trait]
+impl ManageConnection for OdbcManager {
+    type Connection = Slotted<Connection<'static>>;
+    type Error = String;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        static ENV: Lazy<Result<odbc_api::Environment, String>> = Lazy::new(|| {
+            unsafe { odbc_api::Environment::new() }.map_err(|e| format!("Failed to create ODBC Environment: {e}"))
+        });
+        let env = ENV.as_ref().map_err(Clone::clone)?;
+        let slot = connection_slot().await?;
+        let conn = tokio::task::block_in_place(|| env.connect_with_connection_string(&self.conn_str, odbc_api::ConnectionOptions::default()))
+            .map_err(|e| e.to_string())?;
+        Ok(Slotted { conn, broken: false, _slot: slot })
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        tokio::task::block_in_place(|| conn.execute("SELECT 1", ())).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    // Asks the driver, which notices a dropped link without a round trip
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.broken || conn.is_dead().unwrap_or(true)
+    }
+}
+
+#[cfg(feature = "tiberius")]
+pub(crate) type TiberiusClient = tiberius::Client<tokio_util::compat::Compat<tokio::net::TcpStream>>;
+
+// Native async connection manager for bb8; needs no ODBC driver on the host
+#[cfg(feature = "tiberius")]
+#[derive(Clone, Debug)]
+pub(crate) struct TiberiusManager {
+    pub(crate) config: tiberius::Config,
+}
+
+#[cfg(feature = "tiberius")]
+#[async_trait]
+impl ManageConnection for TiberiusManager {
+    type Connection = Slotted<TiberiusClient>;
+    type Error = String;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        use tiberius::SqlBrowser;
+        use tokio_util::compat::TokioAsyncWriteCompatExt;
+        let slot = connection_slot().await?;
+        // Resolves named instances through SQL Browser, plain host:port otherwise
+        let tcp = tokio::net::TcpStream::connect_named(&self.config).await.map_err(|e| e.to_string())?;
+        tcp.set_nodelay(true).map_err(|e| e.to_string())?;
+        let conn = tiberius::Client::connect(self.config.clone(), tcp.compat_write()).await.map_err(|e| e.to_string())?;
+        Ok(Slotted { conn, broken: false, _slot: slot })
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        conn.simple_query("SELECT 1").await.map_err(|e| e.to_string())?
+            .into_results().await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    // tiberius has no cheap liveness check, so rely on the flag set by with_connection
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        conn.broken
+    }
+}
+
+// Which driver the server reaches SQL Server through, from DATABASE_BACKEND
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Backend {
+    Odbc,
+    Tiberius,
+}
+
+// One per database, over whichever backend is configured
+pub(crate) enum DbPool {
+    #[cfg(feature = "odbc")]
+    Odbc(Pool<OdbcManager>),
+    #[cfg(feature = "tiberius")]
+    Tiberius(Pool<TiberiusManager>),
+}
+
+impl DbPool {
+    pub(crate) async fn get(&self) -> Result<DbConn, AppError> {
+        Ok(match self {
+            #[cfg(feature = "odbc")]
+            DbPool::Odbc(pool) => DbConn::Odbc(pool.get_owned().await?),
+            #[cfg(feature = "tiberius")]
+            DbPool::Tiberius(pool) => DbConn::Tiberius(Box::new(pool.get_owned().await?)),
+        })
+    }
+
+    pub(crate) fn state(&self) -> bb8::State {
+        match self {
+            #[cfg(feature = "odbc")]
+            DbPool::Odbc(pool) => pool.state(),
+            #[cfg(feature = "tiberius")]
+            DbPool::Tiberius(pool) => pool.state(),
+        }
+    }
+}
+
+// A database's pool in POOL_CACHE, with when it last handed out a connection
+pub(crate) struct PoolEntry {
+    pub(crate) pool: DbPool,
+    pub(crate) created_at: u64,
+    pub(crate) last_used: std::sync::atomic::AtomicU64,
+}
+
+impl PoolEntry {
+    pub(crate) async fn get(&self) -> Result<DbConn, AppError> {
+        self.last_used.store(unix_now(), std::sync::atomic::Ordering::Relaxed);
+        self.pool.get().await
+    }
+
+    pub(crate) fn in_use(&self) -> u32 {
+        let state = self.pool.state();
+        state.connections - state.idle_connections
+    }
+}
+
+// Close the pools of databases unused for `idle_secs`; the master pool and any
+// pool with a connection checked out are kept
+pub(crate) fn evict_idle_pools(idle_secs: u64) {
+    let cutoff = unix_now().saturating_sub(idle_secs);
+    POOL_CACHE.retain(|name, entry| {
+        name == "master" || entry.in_use() > 0 || entry.last_used.load(std::sync::atomic::Ordering::Relaxed) >= cutoff
+    });
+}
+
+// A pooled connection; the query helpers below work on either kind
+pub(crate) enum DbConn {
+    #[cfg(feature = "odbc")]
+    Odbc(bb8::PooledConnection<'static, OdbcManager>),
+    #[cfg(feature = "tiberius")]
+    Tiberius(Box<bb8::PooledConnection<'static, TiberiusManager>>),
+}
+
+impl DbConn {
+    // The pool closes it instead of handing it out again
+    pub(crate) fn mark_broken(&mut self) {
+        match self {
+            #[cfg(feature = "odbc")]
+            DbConn::Odbc(conn) => conn.broken = true,
+            #[cfg(feature = "tiberius")]
+            DbConn::Tiberius(conn) => conn.broken = true,
+        }
+    }
+}
+
+// The tiberius connection settings shared by every database's pool. tiberius
+// quietly falls back to a login with an empty user when it cannot use integrated
+// security (anywhere but Windows), so a string without credentials is refused here.
+#[cfg(feature = "tiberius")]
+pub(crate) fn tiberius_config() -> Result<tiberius::Config, String> {
+    let settings: Vec<(String, String)> = CONFIG.tiberius_connection.split(';')
+        .filter_map(|part| part.split_once('='))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_lowercase()))
+        .collect();
+    let integrated = settings.iter()
+        .any(|(key, value)| (key == "integratedsecurity" || key == "integrated security") && matches!(value.as_str(), "true" | "yes" | "sspi"));
+    let has_user = settings.iter()
+        .any(|(key, value)| matches!(key.as_str(), "uid" | "username" | "user" | "user id") && !value.is_empty());
+    if integrated && !cfg!(windows) {
+        return Err("IntegratedSecurity only works on Windows; give User Id and Password instead".to_string());
+    }
+    if !integrated && !has_user {
+        return Err("no User Id given; set DATABASE_TIBERIUS_CONNECTION to e.g. server=tcp:host;User Id=app;Password=...".to_string());
+    }
+    tiberius::Config::from_ado_string(&CONFIG.tiberius_connection).map_err(|e| e.to_string())
+}
+
+pub(crate) fn pool_builder<M: ManageConnection>() -> bb8::Builder<M> {
+    Pool::builder()
+        .max_size(CONFIG.pool_size)
+        .connection_timeout(CONNECTION_WAIT)
+        .idle_timeout(Some(Duration::from_secs(CONFIG.pool_idle_minutes * 60)))
+}
+
+// A connection string value in braces, so `;`, `=` and `}` in it cannot end it early
+#[cfg(feature = "odbc")]
+pub(crate) fn odbc_value(value: &str) -> String {
+    format!("{{{}}}", value.replace('}', "}}"))
+}
+
+// POOL_CACHE key for a database: SQL Server matches database names without
+// regard to case, so "Project" and "project " share one pool
+pub(crate) fn pool_key(db_name: &str) -> String {
+    db_name.trim().to_lowercase()
+}
+
+// Helper to get or create a pool for a given database. Connections are opened
+// on first use, so this only fails on bad settings.
+pub(crate) fn get_or_create_pool(db_name: &str) -> Result<Arc<PoolEntry>, AppError> {
+    use dashmap::mapref::entry::Entry;
+    let db_name = db_name.trim();
+    let entry = match POOL_CACHE.entry(pool_key(db_name)) {
+        Entry::Occupied(entry) => return Ok(entry.get().clone()),
+        Entry::Vacant(entry) => entry,
+    };
+    let pool = match CONFIG.backend {
+        #[cfg(feature = "odbc")]
+        Backend::Odbc => {
+            let conn_str = format!(
+                "Driver={};Server={};Database={};Trusted_Connection=Yes;",
+                odbc_value(&CONFIG.odbc_driver),
+                odbc_value(&CONFIG.server),
+                odbc_value(db_name),
+            );
+            DbPool::Odbc(pool_builder().build_unchecked(OdbcManager { conn_str }))
+        }
+        #[cfg(feature = "tiberius")]
+        Backend::Tiberius => {
+            let mut config = tiberius_config().map_err(|e| AppError::internal(format!("Invalid DATABASE_TIBERIUS_CONNECTION: {e}")))?;
+            config.database(db_name);
+            DbPool::Tiberius(pool_builder().build_unchecked(TiberiusManager { config }))
+        }
+        #[allow(unreachable_patterns)]
+        backend => return Err(AppError::internal(format!("This build has no {backend:?} backend; enable its cargo feature"))),
+    };
+    let now = unix_now();
+    let pool = Arc::new(PoolEntry { pool, created_at: now, last_used: std::sync::atomic::AtomicU64::new(now) });
+    entry.insert(pool.clone());
+    Ok(pool)
+}
+
+#[derive(Serialize)]
+pub(crate) struct PoolStats {
+    pub(crate) database: String,
+    pub(crate) max_size: u32,
+    pub(crate) connections: u32,
+    pub(crate) idle: u32,
+    pub(crate) in_use: u32,
+    pub(crate) created_at: u64,
+    pub(crate) last_used: u64,
+}
+
+pub(crate) async fn admin_list_pools() -> axum::response::Response {
+    let mut pools: Vec<PoolStats> = POOL_CACHE.iter()
+        .map(|entry| {
+            let state = entry.pool.state();
+            PoolStats {
+                database: entry.key().clone(),
+                max_size: CONFIG.pool_size,
+                connections: state.connections,
+                idle: state.idle_connections,
+                in_use: state.connections - state.idle_connections,
+                created_at: entry.created_at,
+                last_used: entry.last_used.load(std::sync::atomic::Ordering::Relaxed),
+            }
+        })
+        .collect();
+    pools.sort_by(|a, b| a.database.cmp(&b.database));
+    Json(serde_json::json!({
+        "backend": CONFIG.backend,
+        "max_connections": CONFIG.max_connections,
+        "open_connections": CONFIG.max_connections - CONNECTION_SLOTS.available_permits(),
+        "idle_minutes": CONFIG.pool_idle_minutes,
+        "pools": pools,
+    })).into_response()
+}
@@ -0,0 +1,607 @@
+// Equipment rollup, room data sheets and the schedule of accommodation
+
+use super::*;
+
+// Every non-ignored room's effective item list (see RoomItems), totalled per item.
+// A per-room row with its Ignore_flag set hides the type row without counting itself.
+pub(crate) const EQUIPMENT_ROLLUP_SQL: &str = concat!(
+    "WITH rooms AS (SELECT [Room_Id], [Room_Code] FROM Room_Schedule WHERE NOT ", flag_set!("[Ignore_Flag]"), "), ",
+    "effective AS (",
+    "SELECT r.[Room_Id], s.[Item_Ref], ISNULL(", as_number!("s.[Qty_New]"), ", 0) AS [Qty_New], ISNULL(", as_number!("s.[Qty_Trans]"), ", 0) AS [Qty_Trans] ",
+    "FROM rooms r JOIN Item_Schedule s ON s.[Room_Code] = r.[Room_Code] ",
+    "WHERE NOT ", flag_set!("s.[Ignore_flag]"), " AND (",
+    "(ISNULL(s.[instance_variant], 0) = 0 AND NOT EXISTS (SELECT 1 FROM Item_Schedule o WHERE o.[Room_Code] = s.[Room_Code] ",
+    "AND CONVERT(nvarchar(100), o.[instance_variant]) = CONVERT(nvarchar(100), r.[Room_Id]) AND o.[Item_Ref] = s.[Item_Ref])) ",
+    "OR CONVERT(nvarchar(100), s.[instance_variant]) = CONVERT(nvarchar(100), r.[Room_Id]))) ",
+    "SELECT e.[Item_Ref], d.[Item_Description], m.[Cat], m.[Group], COUNT(DISTINCT e.[Room_Id]) AS [Rooms], ",
+    "SUM(e.[Qty_New]) AS [Qty_New], SUM(e.[Qty_Trans]) AS [Qty_Trans], SUM(e.[Qty_New] + e.[Qty_Trans]) AS [Qty_Total] ",
+    "FROM effective e LEFT JOIN Item_descriptions d ON d.[ADB_Ref] = e.[Item_Ref] LEFT JOIN ERM m ON m.[ADB_Code] = e.[Item_Ref] ",
+    "WHERE e.[Qty_New] <> 0 OR e.[Qty_Trans] <> 0 ",
+    "GROUP BY e.[Item_Ref], d.[Item_Description], m.[Cat], m.[Group] ORDER BY m.[Cat], e.[Item_Ref]"
+);
+
+pub(crate) const ROOM_COUNT_SQL: &str = concat!(
+    "SELECT SUM(CASE WHEN ", flag_set!("[Ignore_Flag]"), " THEN 0 ELSE 1 END), SUM(CASE WHEN ", flag_set!("[Ignore_Flag]"), " THEN 1 ELSE 0 END) FROM Room_Schedule"
+);
+
+#[derive(Serialize)]
+pub(crate) struct EquipmentRollup {
+    pub(crate) rooms: u64,
+    pub(crate) ignored_rooms: u64,
+    // Project totals per Item_Ref
+    pub(crate) items: TableData,
+    // The same totals per ERM Cat
+    pub(crate) categories: TableData,
+}
+
+pub(crate) fn category_totals(items: &TableData) -> TableData {
+    let mut totals: Vec<(String, u64, [f64; 3])> = Vec::new();
+    for row in &items.rows {
+        let cat = match &*items.cell(row, "Cat") {
+            "" => "(no ERM category)".to_string(),
+            cat => cat.to_string(),
+        };
+        let qty = ["Qty_New", "Qty_Trans", "Qty_Total"].map(|c| items.number(row, c));
+        match totals.iter_mut().find(|(c, _, _)| *c == cat) {
+            Some((_, count, sums)) => {
+                *count += 1;
+                sums.iter_mut().zip(qty).for_each(|(s, q)| *s += q);
+            }
+            None => totals.push((cat, 1, qty)),
+        }
+    }
+    TableData {
+        columns: ["Cat", "Items", "Qty_New", "Qty_Trans", "Qty_Total"].map(String::from).to_vec(),
+        rows: totals.into_iter()
+            .map(|(cat, count, sums)| [cat.into(), count.into()].into_iter().chain(sums.map(json_number)).collect())
+            .collect(),
+        ..Default::default()
+    }
+}
+
+// Page for the equipment quantity rollup
+pub(crate) async fn equipment_report_page(Path(_db_name): Path<String>) -> impl IntoResponse {
+    Html(include_str!("../static/equipment.html"))
+}
+
+// Handler for project equipment quantities: each room's items, added up over every room not ignored
+pub(crate) async fn equipment_report(Path(db_name): Path<String>) -> axum::response::Response {
+    let result = with_connection(&db_name, move |conn| Box::pin(async move {
+        let counts = query_table(conn, ROOM_COUNT_SQL, &[]).await?;
+        let count = |i: usize| counts.rows.first().and_then(|r| r.get(i)).and_then(|c| c.as_u64()).unwrap_or(0);
+        let items = query_table(conn, EQUIPMENT_ROLLUP_SQL, &[]).await?;
+        let categories = category_totals(&items);
+        Ok(EquipmentRollup { rooms: count(0), ignored_rooms: count(1), items, categories })
+    })).await;
+    match result {
+        Ok(rollup) => Json(rollup).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Which rooms a room data sheet request covers
+pub(crate) enum SheetScope {
+    Room(String),
+    Areaslevel1(String),
+    Areaslevel2(String),
+}
+
+// A4 portrait, in mm
+pub(crate) const SHEET_WIDTH: f32 = 210.0;
+pub(crate) const SHEET_HEIGHT: f32 = 297.0;
+pub(crate) const SHEET_MARGIN: f32 = 15.0;
+
+// Writes text top to bottom, starting a new page when the current one is full
+pub(crate) struct SheetWriter {
+    pub(crate) doc: printpdf::PdfDocumentReference,
+    pub(crate) layer: printpdf::PdfLayerReference,
+    pub(crate) regular: printpdf::IndirectFontRef,
+    pub(crate) bold: printpdf::IndirectFontRef,
+    pub(crate) y: f32,
+}
+
+impl SheetWriter {
+    pub(crate) fn new(title: &str) -> Result<SheetWriter, AppError> {
+        use printpdf::{BuiltinFont, Mm, PdfDocument};
+        let (doc, page, layer) = PdfDocument::new(title, Mm(SHEET_WIDTH), Mm(SHEET_HEIGHT), "Layer 1");
+        let regular = doc.add_builtin_font(BuiltinFont::Helvetica).map_err(AppError::internal)?;
+        let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold).map_err(AppError::internal)?;
+        let layer = doc.get_page(page).get_layer(layer);
+        Ok(SheetWriter { doc, layer, regular, bold, y: SHEET_HEIGHT - SHEET_MARGIN })
+    }
+
+    pub(crate) fn new_page(&mut self) {
+        let (page, layer) = self.doc.add_page(printpdf::Mm(SHEET_WIDTH), printpdf::Mm(SHEET_HEIGHT), "Layer 1");
+        self.layer = self.doc.get_page(page).get_layer(layer);
+        self.y = SHEET_HEIGHT - SHEET_MARGIN;
+    }
+
+    // Start a new page unless `height` mm still fit on this one
+    pub(crate) fn ensure(&mut self, height: f32) -> bool {
+        if self.y - height < SHEET_MARGIN {
+            self.new_page();
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn text(&self, x: f32, size: f32, bold: bool, text: &str) {
+        let font = if bold { &self.bold } else { &self.regular };
+        self.layer.use_text(text, size, printpdf::Mm(x), printpdf::Mm(self.y), font);
+    }
+
+    pub(crate) fn rule(&self) {
+        use printpdf::{Line, Mm, Point};
+        let y = self.y + 1.5;
+        self.layer.add_line(Line {
+            points: vec![(Point::new(Mm(SHEET_MARGIN), Mm(y)), false), (Point::new(Mm(SHEET_WIDTH - SHEET_MARGIN), Mm(y)), false)],
+            is_closed: false,
+        });
+    }
+
+    pub(crate) fn advance(&mut self, height: f32) {
+        self.y -= height;
+    }
+
+    pub(crate) fn finish(self) -> Result<Vec<u8>, AppError> {
+        self.doc.save_to_bytes().map_err(AppError::internal)
+    }
+}
+
+// Builtin PDF fonts have no metrics here, so wrap on an average Helvetica glyph width
+pub(crate) fn wrap_text(text: &str, width_mm: f32, size: f32) -> Vec<String> {
+    let max_chars = ((width_mm / (size * 0.5 * 0.3528)) as usize).max(1);
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > max_chars {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+            while line.chars().count() > max_chars {
+                let rest = line.chars().skip(max_chars).collect();
+                lines.push(line.chars().take(max_chars).collect());
+                line = rest;
+            }
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+// Item table columns: heading, x offset from the margin, width, Item_Schedule column
+pub(crate) const SHEET_ITEM_COLUMNS: [(&str, f32, f32, &str); 6] = [
+    ("Item_Ref", 0.0, 22.0, "Item_Ref"),
+    ("Description", 22.0, 70.0, "Item_Description"),
+    ("ERM Cat", 92.0, 22.0, "Cat"),
+    ("Qty New", 114.0, 14.0, "Qty_New"),
+    ("Qty Trans", 128.0, 14.0, "Qty_Trans"),
+    ("Notes", 142.0, 38.0, "Notes"),
+];
+
+pub(crate) fn write_item_header(sheet: &mut SheetWriter) {
+    for (heading, x, _, _) in SHEET_ITEM_COLUMNS {
+        sheet.text(SHEET_MARGIN + x, 8.5, true, heading);
+    }
+    sheet.rule();
+    sheet.advance(5.0);
+}
+
+// One room's data sheet, starting on a fresh page
+pub(crate) fn write_room_sheet(sheet: &mut SheetWriter, db_name: &str, room: &TableData, row: &[serde_json::Value], area_path: &str, items: &TableData) {
+    sheet.text(SHEET_MARGIN, 16.0, true, "Room Data Sheet");
+    sheet.text(SHEET_WIDTH - SHEET_MARGIN - 50.0, 9.0, false, db_name);
+    sheet.advance(10.0);
+    let fields = [
+        ("Room_Id", room.cell(row, "Room_Id").to_string()),
+        ("Description", room.cell(row, "Project_Room_Description").to_string()),
+        ("Room_Code", room.cell(row, "Room_Code").to_string()),
+        ("Area", format!("{} m\u{b2}", room.cell(row, "Area"))),
+        ("Location", area_path.to_string()),
+        ("Ignore_Flag", room.cell(row, "Ignore_Flag").to_string()),
+        ("Notes", room.cell(row, "Internal_Notes").to_string()),
+    ];
+    for (label, value) in fields {
+        let lines = wrap_text(&value, SHEET_WIDTH - 2.0 * SHEET_MARGIN - 35.0, 10.0);
+        sheet.ensure(5.5 * lines.len() as f32);
+        sheet.text(SHEET_MARGIN, 10.0, true, label);
+        for line in lines {
+            sheet.text(SHEET_MARGIN + 35.0, 10.0, false, &line);
+            sheet.advance(5.5);
+        }
+    }
+    sheet.advance(6.0);
+    sheet.ensure(20.0);
+    sheet.text(SHEET_MARGIN, 12.0, true, "Item Schedule");
+    sheet.advance(7.0);
+    write_item_header(sheet);
+    if items.rows.is_empty() {
+        sheet.text(SHEET_MARGIN, 9.0, false, "No items scheduled for this room.");
+        sheet.advance(5.0);
+    }
+    for item in &items.rows {
+        let cells: Vec<Vec<String>> = SHEET_ITEM_COLUMNS.iter()
+            .map(|(_, _, width, column)| wrap_text(&items.cell(item, column), *width - 1.0, 8.5))
+            .collect();
+        let height = 4.2 * cells.iter().map(|c| c.len()).max().unwrap_or(1) as f32 + 1.0;
+        if sheet.ensure(height) {
+            write_item_header(sheet);
+        }
+        let top = sheet.y;
+        for ((_, x, _, _), lines) in SHEET_ITEM_COLUMNS.iter().zip(&cells) {
+            sheet.y = top;
+            for line in lines {
+                sheet.text(SHEET_MARGIN + x, 8.5, false, line);
+                sheet.advance(4.2);
+            }
+        }
+        sheet.y = top - height;
+    }
+}
+
+// Everything a room data sheet document shows, read before a page is written
+pub(crate) struct RoomSheetData {
+    pub(crate) db_name: String,
+    pub(crate) title: String,
+    pub(crate) rooms: TableData,
+    // Areaslevel3 > 2 > 1 path by the rooms' ParentArea
+    pub(crate) paths: HashMap<String, String>,
+    // ParentArea and effective item list, in the order of `rooms`
+    pub(crate) room_lists: Vec<(String, RoomItems)>,
+}
+
+// Rooms in scope with their Areaslevel3 > 2 > 1 path and item lists.
+// `progress` hears (rooms done, rooms in scope) before each room and may stop the read.
+pub(crate) async fn read_room_sheets(
+    conn: &mut DbConn,
+    db_name: &str,
+    scope: &SheetScope,
+    progress: &(dyn Fn(usize, usize) -> Result<(), AppError> + Sync),
+) -> Result<RoomSheetData, AppError> {
+    let (filter, key, title) = match scope {
+        SheetScope::Room(id) => ("WHERE [Room_Id] = ?", id, format!("Room {}", id)),
+        SheetScope::Areaslevel1(id) => ("WHERE [ParentArea] = ?", id, format!("Areaslevel1 {}", id)),
+        SheetScope::Areaslevel2(id) => (
+            "WHERE [ParentArea] IN (SELECT [ArealevelID] FROM Areaslevel1 WHERE [ParentArea] = ?)",
+            id,
+            format!("Areaslevel2 {}", id),
+        ),
+    };
+    let rooms = query_table(conn, &format!("{} {} ORDER BY [ParentArea], [Room_Id]", ROOM_SCHEDULE_SELECT, filter), &[Some(key.as_str())]).await?;
+    if rooms.rows.is_empty() {
+        return Err(match scope {
+            SheetScope::Room(id) => missing_row("Room_Schedule", "Room_Id", id),
+            _ => AppError::not_found(format!("No rooms in {}", title)),
+        });
+    }
+    let mut paths: HashMap<String, String> = HashMap::new();
+    let mut room_lists = Vec::with_capacity(rooms.rows.len());
+    for (i, row) in rooms.rows.iter().enumerate() {
+        progress(i, rooms.rows.len())?;
+        let parent = rooms.cell(row, "ParentArea").to_string();
+        if !paths.contains_key(&parent) {
+            let found = query_table(
+                conn,
+                "SELECT a3.[AreaDescription], a2.[AreaDescription], a1.[AreaDescription] FROM Areaslevel1 a1 \
+                 LEFT JOIN Areaslevel2 a2 ON a2.[ArealevelID] = a1.[ParentArea] \
+                 LEFT JOIN Areaslevel3 a3 ON a3.[ArealevelID] = a2.[ParentArea] WHERE a1.[ArealevelID] = ?",
+                &[Some(parent.as_str())],
+            ).await?;
+            let path = found.rows.first()
+                .map(|r| r.iter().map(cell_text).filter(|s| !s.is_empty()).collect::<Vec<_>>().join(" > "))
+                .unwrap_or_else(|| format!("(unknown area {})", parent));
+            paths.insert(parent.clone(), path);
+        }
+        let items = room_items(conn, &rooms.cell(row, "Room_Id"), &rooms.cell(row, "Room_Code"), false).await?;
+        room_lists.push((parent, items));
+    }
+    Ok(RoomSheetData { db_name: db_name.to_string(), title, rooms, paths, room_lists })
+}
+
+// One PDF sheet per room. CPU-bound, so callers run it on the blocking pool.
+pub(crate) fn render_room_sheets(data: &RoomSheetData) -> Result<(String, Vec<u8>), AppError> {
+    let RoomSheetData { db_name, title, rooms, paths, room_lists } = data;
+    let mut sheet = SheetWriter::new(&format!("Room Data Sheets - {} - {}", db_name, title))?;
+    for (i, (row, (parent, items))) in rooms.rows.iter().zip(room_lists).enumerate() {
+        if i > 0 {
+            sheet.new_page();
+        }
+        write_room_sheet(&mut sheet, db_name, rooms, row, &paths[parent], &items.items);
+    }
+    let file_name = format!("{} {}.pdf", db_name, title).replace(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-', "_");
+    Ok((file_name, sheet.finish()?))
+}
+
+pub(crate) async fn room_sheets_response(db_name: String, scope: SheetScope) -> axum::response::Response {
+    let data = with_connection(&db_name, {
+        let db_name = db_name.clone();
+        move |conn| Box::pin(async move {
+            read_room_sheets(conn, &db_name, &scope, &|_, _| Ok(())).await
+        })
+    }).await;
+    let result = match data {
+        Ok(data) => tokio::task::spawn_blocking(move || render_room_sheets(&data)).await.map_err(AppError::from).and_then(|r| r),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok((file_name, pdf)) => (
+            [
+                (axum::http::header::CONTENT_TYPE, "application/pdf".to_string()),
+                (axum::http::header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", file_name)),
+            ],
+            pdf,
+        ).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handler for one room's data sheet
+pub(crate) async fn room_sheet(Path((db_name, room_id)): Path<(String, String)>) -> axum::response::Response {
+    room_sheets_response(db_name, SheetScope::Room(room_id)).await
+}
+
+// Handler for the data sheets of every room in an Areaslevel1 area
+pub(crate) async fn areaslevel1_room_sheets(Path((db_name, arealevel_id)): Path<(String, String)>) -> axum::response::Response {
+    room_sheets_response(db_name, SheetScope::Areaslevel1(arealevel_id)).await
+}
+
+// Handler for the data sheets of every room under an Areaslevel2 area
+pub(crate) async fn areaslevel2_room_sheets(Path((db_name, arealevel_id)): Path<(String, String)>) -> axum::response::Response {
+    room_sheets_response(db_name, SheetScope::Areaslevel2(arealevel_id)).await
+}
+
+// One line of the schedule of accommodation. `kind` is "area" (a heading),
+// "room", "subtotal", "total" or "section" (the ignored and unassigned room lists).
+#[derive(Serialize)]
+pub(crate) struct AccommodationLine {
+    pub(crate) kind: &'static str,
+    // 3, 2 or 1 for area headings and subtotals, 0 otherwise
+    pub(crate) level: usize,
+    pub(crate) id: String,
+    pub(crate) description: String,
+    pub(crate) code: String,
+    pub(crate) area: f64,
+    pub(crate) gross: f64,
+    pub(crate) quantity: u64,
+}
+
+impl AccommodationLine {
+    pub(crate) fn heading(kind: &'static str, level: usize, id: &str, description: &str) -> AccommodationLine {
+        AccommodationLine { kind, level, id: id.to_string(), description: description.to_string(), code: String::new(), area: 0.0, gross: 0.0, quantity: 0 }
+    }
+
+    // Totals are rounded by the project's rules; room areas are shown as entered
+    pub(crate) fn subtotal(kind: &'static str, level: usize, id: &str, description: String, total: AreaTotal, rules: &RollupRules) -> AccommodationLine {
+        AccommodationLine {
+            kind,
+            level,
+            id: id.to_string(),
+            description,
+            code: String::new(),
+            area: rules.round(total.area),
+            gross: rules.round(total.gross),
+            quantity: total.rooms,
+        }
+    }
+
+    pub(crate) fn room(rooms: &TableData, row: &[serde_json::Value]) -> AccommodationLine {
+        AccommodationLine {
+            kind: "room",
+            level: 0,
+            id: rooms.cell(row, "Room_Id").to_string(),
+            description: rooms.cell(row, "Project_Room_Description").to_string(),
+            code: rooms.cell(row, "Room_Code").to_string(),
+            area: room_area(rooms, row),
+            gross: room_area(rooms, row),
+            quantity: 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct AccommodationReport {
+    pub(crate) database: String,
+    pub(crate) rules: RollupRules,
+    pub(crate) total: AreaTotal,
+    pub(crate) lines: Vec<AccommodationLine>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ReportQuery {
+    pub(crate) format: Option<String>,
+}
+
+// Every Areaslevel3 > 2 > 1 > room with subtotals from AreaRollup. Ignored rooms
+// and rooms outside the hierarchy are listed after the grand total, not in it.
+pub(crate) fn accommodation_report(db_name: &str, tables: &AreaTables, rules: &RollupRules) -> AccommodationReport {
+    let rules = RollupRules { exclude_ignored: true, ..rules.clone() };
+    let rollup = tables.rollup(&rules);
+    let AreaTables { a3, a2, a1, rooms } = tables;
+    let children = |t: &'_ TableData, parent: &str| -> Vec<usize> {
+        (0..t.rows.len()).filter(|&i| same_key(&t.cell(&t.rows[i], "ParentArea"), parent)).collect()
+    };
+    let mut lines = Vec::new();
+    let mut placed = vec![false; rooms.rows.len()];
+    let mut total = AreaTotal::default();
+    for r3 in &a3.rows {
+        let id3 = a3.cell(r3, "ArealevelID");
+        lines.push(AccommodationLine::heading("area", 3, &id3, &a3.cell(r3, "AreaDescription")));
+        for i2 in children(a2, &id3) {
+            let r2 = &a2.rows[i2];
+            let id2 = a2.cell(r2, "ArealevelID");
+            lines.push(AccommodationLine::heading("area", 2, &id2, &a2.cell(r2, "AreaDescription")));
+            for i1 in children(a1, &id2) {
+                let r1 = &a1.rows[i1];
+                let id1 = a1.cell(r1, "ArealevelID");
+                lines.push(AccommodationLine::heading("area", 1, &id1, &a1.cell(r1, "AreaDescription")));
+                for i in children(rooms, &id1) {
+                    if !flag_is_set(&rooms.cell(&rooms.rows[i], "Ignore_Flag")) {
+                        placed[i] = true;
+                        lines.push(AccommodationLine::room(rooms, &rooms.rows[i]));
+                    }
+                }
+                lines.push(AccommodationLine::subtotal("subtotal", 1, &id1, format!("Total {}", a1.cell(r1, "AreaDescription")), rollup.total(1, &id1), &rules));
+            }
+            lines.push(AccommodationLine::subtotal("subtotal", 2, &id2, format!("Total {}", a2.cell(r2, "AreaDescription")), rollup.total(2, &id2), &rules));
+        }
+        total.add(rollup.total(3, &id3));
+        lines.push(AccommodationLine::subtotal("subtotal", 3, &id3, format!("Total {}", a3.cell(r3, "AreaDescription")), rollup.total(3, &id3), &rules));
+    }
+    lines.push(AccommodationLine::subtotal("total", 0, "", "Grand total".to_string(), total, &rules));
+    let (ignored, unplaced): (Vec<usize>, Vec<usize>) = (0..rooms.rows.len())
+        .filter(|&i| !placed[i])
+        .partition(|&i| flag_is_set(&rooms.cell(&rooms.rows[i], "Ignore_Flag")));
+    for (title, listed) in [("Rooms with Ignore_Flag set", ignored), ("Rooms outside the area hierarchy", unplaced)] {
+        if listed.is_empty() {
+            continue;
+        }
+        lines.push(AccommodationLine::heading("section", 0, "", title));
+        let mut subtotal = AreaTotal::default();
+        for i in listed {
+            let line = AccommodationLine::room(rooms, &rooms.rows[i]);
+            subtotal.add(AreaTotal::room(line.area));
+            lines.push(line);
+        }
+        lines.push(AccommodationLine::subtotal("subtotal", 0, "", format!("Total {}", title.to_lowercase()), subtotal, &rules));
+    }
+    let total = AreaTotal { area: rules.round(total.area), gross: rules.round(total.gross), ..total };
+    AccommodationReport { database: db_name.to_string(), rules, total, lines }
+}
+
+pub(crate) fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Area, gross area and quantity as printed; blank on headings
+pub(crate) fn accommodation_figures(report: &AccommodationReport, line: &AccommodationLine) -> (String, String, String) {
+    match line.kind {
+        "area" | "section" => (String::new(), String::new(), String::new()),
+        "room" => (format!("{:.2}", line.area), String::new(), line.quantity.to_string()),
+        _ => (report.rules.format(line.area), report.rules.format(line.gross), line.quantity.to_string()),
+    }
+}
+
+pub(crate) fn accommodation_csv(report: &AccommodationReport) -> String {
+    let mut csv = String::from("Type,Level,ID,Description,Room_Code,Area,Gross_Area,Quantity\r\n");
+    for line in &report.lines {
+        let (area, gross, quantity) = accommodation_figures(report, line);
+        let level = if line.level == 0 { String::new() } else { format!("Areaslevel{}", line.level) };
+        let fields = [line.kind, &level, &line.id, &line.description, &line.code, &area, &gross, &quantity];
+        csv.push_str(&fields.map(csv_field).join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Schedule columns: heading and x offset from the margin
+pub(crate) const ACCOMMODATION_COLUMNS: [(&str, f32); 6] = [("ID", 0.0), ("Description", 30.0), ("Room_Code", 100.0), ("Area", 125.0), ("Gross", 145.0), ("Qty", 168.0)];
+
+pub(crate) fn accommodation_pdf(report: &AccommodationReport) -> Result<Vec<u8>, AppError> {
+    let mut sheet = SheetWriter::new(&format!("Schedule of Accommodation - {}", report.database))?;
+    let header = |sheet: &mut SheetWriter| {
+        for (heading, x) in ACCOMMODATION_COLUMNS {
+            sheet.text(SHEET_MARGIN + x, 8.5, true, heading);
+        }
+        sheet.rule();
+        sheet.advance(5.0);
+    };
+    sheet.text(SHEET_MARGIN, 16.0, true, "Schedule of Accommodation");
+    sheet.text(SHEET_WIDTH - SHEET_MARGIN - 50.0, 9.0, false, &report.database);
+    sheet.advance(10.0);
+    header(&mut sheet);
+    for line in &report.lines {
+        let bold = line.kind != "room";
+        let gap = if matches!(line.kind, "area" | "section") { 2.0 } else { 0.0 };
+        if sheet.ensure(4.5 + gap) {
+            header(&mut sheet);
+        }
+        sheet.advance(gap);
+        let indent = match (line.kind, line.level) {
+            ("room", _) => 9.0,
+            (_, level) if level > 0 => (3 - level) as f32 * 3.0,
+            _ => 0.0,
+        };
+        let description: String = line.description.chars().take(52).collect();
+        sheet.text(SHEET_MARGIN + indent, 8.5, bold, &line.id);
+        sheet.text(SHEET_MARGIN + ACCOMMODATION_COLUMNS[1].1, 8.5, bold, &description);
+        sheet.text(SHEET_MARGIN + ACCOMMODATION_COLUMNS[2].1, 8.5, bold, &line.code);
+        let (area, gross, quantity) = accommodation_figures(report, line);
+        sheet.text(SHEET_MARGIN + ACCOMMODATION_COLUMNS[3].1, 8.5, bold, &area);
+        sheet.text(SHEET_MARGIN + ACCOMMODATION_COLUMNS[4].1, 8.5, bold, &gross);
+        sheet.text(SHEET_MARGIN + ACCOMMODATION_COLUMNS[5].1, 8.5, bold, &quantity);
+        if line.kind == "total" {
+            sheet.rule();
+        }
+        sheet.advance(4.5);
+    }
+    sheet.finish()
+}
+
+// Page for the schedule of accommodation
+pub(crate) async fn accommodation_page(Path(_db_name): Path<String>) -> impl IntoResponse {
+    Html(include_str!("../static/accommodation.html"))
+}
+
+// Handler for the schedule of accommodation as JSON, CSV (?format=csv) or PDF (?format=pdf)
+pub(crate) async fn get_accommodation_report(Path(db_name): Path<String>, Query(query): Query<ReportQuery>) -> axum::response::Response {
+    let format = query.format.unwrap_or_else(|| "json".to_string()).to_lowercase();
+    if !matches!(format.as_str(), "json" | "csv" | "pdf") {
+        return AppError::validation(format!("Unknown report format {}; use json, csv or pdf", format)).into_response();
+    }
+    let rules = rollup_rules(&db_name).await;
+    let tables = match with_connection(&db_name, |conn| Box::pin(async move { AreaTables::load(conn).await })).await {
+        Ok(tables) => tables,
+        Err(e) => return e.into_response(),
+    };
+    // The report is built off the runtime, with the connection already back in the pool
+    let result = tokio::task::spawn_blocking(move || -> Result<axum::response::Response, AppError> {
+        let report = accommodation_report(&db_name, &tables, &rules);
+        let file_name = format!("{} schedule of accommodation", safe_file_name(&db_name));
+        Ok(match format.as_str() {
+            "csv" => (
+                [
+                    (axum::http::header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                    (axum::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.csv\"", file_name)),
+                ],
+                accommodation_csv(&report),
+            ).into_response(),
+            "pdf" => (
+                [
+                    (axum::http::header::CONTENT_TYPE, "application/pdf".to_string()),
+                    (axum::http::header::CONTENT_DISPOSITION, format!("inline; filename=\"{}.pdf\"", file_name)),
+                ],
+                accommodation_pdf(&report)?,
+            ).into_response(),
+            _ => Json(report).into_response(),
+        })
+    }).await;
+    match result.map_err(AppError::from).and_then(|r| r) {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_text_breaks_on_words_and_splits_long_ones() {
+        // 18 mm at 10 pt holds 10 characters
+        assert_eq!(wrap_text("hello world again", 18.0, 10.0), ["hello", "world", "again"]);
+        assert_eq!(wrap_text("abcdefghijklmnopqrstuvwxy", 18.0, 10.0), ["abcdefghij", "klmnopqrst", "uvwxy"]);
+        assert_eq!(wrap_text("a\nb", 18.0, 10.0), ["a", "b"]);
+        assert_eq!(wrap_text("", 18.0, 10.0), [""]);
+    }
+}
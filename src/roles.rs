@@ -0,0 +1,243 @@
+// Per-database roles and the access each route needs
+
+use super::*;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    Viewer,
+    Planner,
+    Librarian,
+    Admin,
+}
+
+// A role granted to a user on one project database, or on every database with "*"
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Grant {
+    pub(crate) username: String,
+    pub(crate) database: String,
+    pub(crate) role: Role,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct GrantStore {
+    pub(crate) grants: Vec<Grant>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Access {
+    // GET endpoints and pages
+    View,
+    // Areaslevel1-3, Room_Schedule and Item_Schedule
+    EditSchedule,
+    // Room_Types, Item_descriptions and ERM
+    EditLibrary,
+    // EditLibrary for a library table named by the route's :table, EditSchedule otherwise
+    EditTable,
+    // An integrity fix: the route lets any editor through and the handler checks the
+    // fix's own access once the body names it, e.g. EditLibrary for the ERM fix
+    EditFix,
+    // Project settings and issued records: rollup rules, deleting snapshots
+    Manage,
+}
+
+pub(crate) static GRANT_STORE: Lazy<RwLock<GrantStore>> = Lazy::new(|| RwLock::new(load_store("grants.json")));
+
+pub(crate) fn role_allows(role: Role, access: Access) -> bool {
+    match role {
+        Role::Admin => true,
+        Role::Planner => matches!(access, Access::View | Access::EditSchedule | Access::EditFix),
+        Role::Librarian => matches!(access, Access::View | Access::EditLibrary | Access::EditFix),
+        Role::Viewer => access == Access::View,
+    }
+}
+
+// Library tables shared by every room, edited only by librarians
+pub(crate) const LIBRARY_TABLES: [&str; 3] = ["Room_Types", "Item_descriptions", "ERM"];
+
+// A POST route under /api/db/:db_name, the access it needs and whether it changes
+// the project, which is what a project lock freezes
+pub(crate) struct PostRoute {
+    pub(crate) pattern: &'static str,
+    pub(crate) access: Access,
+    pub(crate) mutates: bool,
+}
+
+impl PostRoute {
+    pub(crate) const fn writes(pattern: &'static str, access: Access) -> PostRoute {
+        PostRoute { pattern, access, mutates: true }
+    }
+
+    pub(crate) const fn reads(pattern: &'static str, access: Access) -> PostRoute {
+        PostRoute { pattern, access, mutates: false }
+    }
+}
+
+// Every POST route under /api/db/:db_name. A POST route missing here is refused,
+// so a new route has to be given its access and lock behaviour explicitly.
+pub(crate) const POST_ROUTES: &[PostRoute] = &[
+    PostRoute::writes("/api/db/:db_name/areaslevel3/update", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel3/update_batch", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel3/add", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel3/delete", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel2/update", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel2/update_batch", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel2/add", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel2/delete", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel1/update", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel1/update_batch", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel1/add", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/areaslevel1/delete", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/room_schedule/update", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/room_schedule/update_batch", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/room_schedule/add", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/room_schedule/delete", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/item_schedule/update", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/item_schedule/update_batch", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/item_schedule/add", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/item_schedule/delete", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/room_types/update", Access::EditLibrary),
+    PostRoute::writes("/api/db/:db_name/room_types/update_batch", Access::EditLibrary),
+    PostRoute::writes("/api/db/:db_name/item_descriptions/update", Access::EditLibrary),
+    PostRoute::writes("/api/db/:db_name/item_descriptions/update_batch", Access::EditLibrary),
+    PostRoute::writes("/api/db/:db_name/item_descriptions/update_erm", Access::EditLibrary),
+    PostRoute::writes("/api/db/:db_name/item_descriptions/update_erm_batch", Access::EditLibrary),
+    PostRoute::writes("/api/db/:db_name/table/:table/add", Access::EditTable),
+    PostRoute::writes("/api/db/:db_name/table/:table/update", Access::EditTable),
+    PostRoute::writes("/api/db/:db_name/table/:table/delete", Access::EditTable),
+    PostRoute::writes("/api/db/:db_name/integrity/fix", Access::EditFix),
+    PostRoute::writes("/api/db/:db_name/rollup_rules/update", Access::Manage),
+    // Presence only: says which cell a page is editing, also while locked
+    PostRoute::reads("/api/db/:db_name/editing", Access::View),
+    PostRoute::reads("/api/db/:db_name/snapshots/create", Access::EditSchedule),
+    PostRoute::writes("/api/db/:db_name/snapshots/delete", Access::Manage),
+    // Jobs only read the database, so they run on locked projects too
+    PostRoute::reads("/api/db/:db_name/jobs/start", Access::View),
+];
+
+// The access a POST route needs, or None for a route without an entry
+pub(crate) fn route_access(route: &str, table: Option<&String>) -> Option<Access> {
+    let route = POST_ROUTES.iter().find(|r| r.pattern == route)?;
+    Some(match &route.access {
+        Access::EditTable if table.is_some_and(|t| LIBRARY_TABLES.iter().any(|l| l.eq_ignore_ascii_case(t))) => Access::EditLibrary,
+        Access::EditTable => Access::EditSchedule,
+        access => *access,
+    })
+}
+
+pub(crate) async fn user_roles(username: &str, db_name: &str) -> Vec<Role> {
+    let store = GRANT_STORE.read().await;
+    store.grants.iter()
+        .filter(|g| g.username.eq_ignore_ascii_case(username))
+        .filter(|g| g.database == "*" || g.database.eq_ignore_ascii_case(db_name))
+        .map(|g| g.role)
+        .collect()
+}
+
+pub(crate) async fn is_admin(username: &str) -> bool {
+    let store = GRANT_STORE.read().await;
+    store.grants.iter().any(|g| g.username.eq_ignore_ascii_case(username) && g.database == "*" && g.role == Role::Admin)
+}
+
+// Middleware: check the user's roles on the database named in the route
+pub(crate) async fn require_permission(Path(params): Path<HashMap<String, String>>, req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let Some(user) = req.extensions().get::<CurrentUser>().cloned() else {
+        return AppError::new(ErrorKind::Unauthorized, "Not signed in").into_response();
+    };
+    let db_name = params.get("db_name").cloned().unwrap_or_default();
+    let access = if req.method() == axum::http::Method::GET {
+        Access::View
+    } else {
+        let route = req.extensions().get::<axum::extract::MatchedPath>().map(|m| m.as_str().to_string()).unwrap_or_default();
+        match route_access(&route, params.get("table")) {
+            Some(access) => access,
+            None => return AppError::new(ErrorKind::Forbidden, format!("No access rule for {route}")).into_response(),
+        }
+    };
+    let roles = user_roles(&user.username, &db_name).await;
+    if roles.iter().any(|r| role_allows(*r, access)) {
+        next.run(req).await
+    } else {
+        let msg = format!("{} does not have {:?} permission on {}", user.username, access, db_name);
+        AppError::new(ErrorKind::Forbidden, msg).into_response()
+    }
+}
+
+// Middleware: only global administrators may use /api/admin
+pub(crate) async fn require_admin(req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    match req.extensions().get::<CurrentUser>() {
+        Some(user) if is_admin(&user.username).await => next.run(req).await,
+        _ => AppError::new(ErrorKind::Forbidden, "Administrator role required").into_response(),
+    }
+}
+
+pub(crate) async fn admin_list_grants() -> axum::response::Response {
+    let store = GRANT_STORE.read().await;
+    Json(serde_json::json!({ "grants": store.grants })).into_response()
+}
+
+pub(crate) async fn admin_add_grant(Json(payload): Json<Grant>) -> axum::response::Response {
+    if payload.username.trim().is_empty() || payload.database.trim().is_empty() {
+        return AppError::validation("username and database are required").into_response();
+    }
+    let mut store = GRANT_STORE.write().await;
+    if !store.grants.contains(&payload) {
+        store.grants.push(payload);
+    }
+    match save_store("grants.json", &*store) {
+        Ok(()) => axum::response::Response::new("OK".into()),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+pub(crate) async fn admin_delete_grant(Json(payload): Json<Grant>) -> axum::response::Response {
+    let mut store = GRANT_STORE.write().await;
+    store.grants.retain(|g| {
+        !(g.username.eq_ignore_ascii_case(&payload.username) && g.database.eq_ignore_ascii_case(&payload.database) && g.role == payload.role)
+    });
+    match save_store("grants.json", &*store) {
+        Ok(()) => axum::response::Response::new("OK".into()),
+        Err(e) => AppError::internal(e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_access_follows_the_route_table() {
+        let table = |name: &str| Some(name.to_string());
+        assert_eq!(route_access("/api/db/:db_name/areaslevel3/update", None), Some(Access::EditSchedule));
+        assert_eq!(route_access("/api/db/:db_name/room_types/update_batch", None), Some(Access::EditLibrary));
+        assert_eq!(route_access("/api/db/:db_name/table/:table/update", table("erm").as_ref()), Some(Access::EditLibrary));
+        assert_eq!(route_access("/api/db/:db_name/table/:table/update", table("Room_Schedule").as_ref()), Some(Access::EditSchedule));
+        assert_eq!(route_access("/api/db/:db_name/rollup_rules/update", None), Some(Access::Manage));
+        assert_eq!(route_access("/api/db/:db_name/snapshots/delete", None), Some(Access::Manage));
+        assert_eq!(route_access("/api/db/:db_name/editing", None), Some(Access::View));
+        assert_eq!(route_access("/api/db/:db_name/unknown", None), None);
+    }
+
+    #[test]
+    fn roles_grant_only_their_access() {
+        assert!(role_allows(Role::Planner, Access::EditSchedule));
+        assert!(!role_allows(Role::Planner, Access::EditLibrary));
+        assert!(!role_allows(Role::Planner, Access::Manage));
+        assert!(role_allows(Role::Librarian, Access::EditLibrary));
+        assert!(!role_allows(Role::Librarian, Access::EditSchedule));
+        assert!(role_allows(Role::Viewer, Access::View));
+        assert!(!role_allows(Role::Viewer, Access::EditSchedule));
+        assert!(role_allows(Role::Admin, Access::Manage));
+        assert!(role_allows(Role::Planner, Access::EditFix));
+        assert!(role_allows(Role::Librarian, Access::EditFix));
+        assert!(!role_allows(Role::Viewer, Access::EditFix));
+    }
+
+    #[test]
+    fn route_table_lists_each_route_once() {
+        let mut patterns: Vec<&str> = POST_ROUTES.iter().map(|r| r.pattern).collect();
+        patterns.sort_unstable();
+        patterns.dedup();
+        assert_eq!(patterns.len(), POST_ROUTES.len());
+    }
+}
@@ -0,0 +1,369 @@
+// Point-in-time copies of a project's tables, and diffs against live data
+
+use super::*;
+
+#[derive(Deserialize)]
+pub(crate) struct SnapshotQuery {
+    pub(crate) snapshot: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateSnapshot {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) note: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteSnapshot {
+    pub(crate) name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) name: String,
+    pub(crate) database: String,
+    pub(crate) note: String,
+    pub(crate) created_by: String,
+    pub(crate) created_at: u64,
+    pub(crate) tables: HashMap<String, TableData>,
+    // The rollup rules in force when it was taken, so its totals never change.
+    // Snapshots from before the rules existed read back as the defaults.
+    #[serde(default)]
+    pub(crate) rules: RollupRules,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SnapshotInfo {
+    pub(crate) name: String,
+    pub(crate) note: String,
+    pub(crate) created_by: String,
+    pub(crate) created_at: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CellChange {
+    pub(crate) key: String,
+    pub(crate) column: String,
+    pub(crate) snapshot: serde_json::Value,
+    pub(crate) live: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TableDiff {
+    pub(crate) table: String,
+    pub(crate) key_column: String,
+    pub(crate) added: Vec<serde_json::Map<String, serde_json::Value>>,
+    pub(crate) removed: Vec<serde_json::Map<String, serde_json::Value>>,
+    pub(crate) changed: Vec<CellChange>,
+}
+
+// Tables captured by a snapshot, with the column that identifies a row
+pub(crate) const SNAPSHOT_TABLES: [(&str, &str); 6] = [
+    ("Areaslevel3", "ParentArea"),
+    ("Areaslevel2", "ArealevelID"),
+    ("Areaslevel1", "ArealevelID"),
+    ("Room_Schedule", "Room_Id"),
+    ("Item_Schedule", "Item_schedule_id"),
+    ("Item_descriptions", "ADB_Ref"),
+];
+
+// Which GET endpoint a snapshot is being read through
+pub(crate) enum SnapshotView<'a> {
+    Areaslevel3,
+    Areaslevel2(&'a str),
+    Areaslevel1(&'a str),
+    RoomSchedule(Option<&'a str>),
+    ItemSchedule(&'a str),
+}
+
+// The name is the file name, so one that safe_file_name would rewrite could
+// reach another snapshot's file ("a/b" and "a_b" both end up as a_b.json)
+pub(crate) fn check_snapshot_name(name: &str) -> Result<(), AppError> {
+    if name.is_empty() || safe_file_name(name) != name {
+        return Err(AppError::validation("Snapshot names may only use letters, digits, spaces, '-' and '_'"));
+    }
+    Ok(())
+}
+
+pub(crate) fn snapshot_path(db_name: &str, name: &str) -> Result<String, AppError> {
+    check_snapshot_name(name)?;
+    Ok(format!("snapshots/{}/{}.json", safe_file_name(&db_name.to_lowercase()), name))
+}
+
+pub(crate) fn load_snapshot(db_name: &str, name: &str) -> Result<Snapshot, AppError> {
+    let path = CONFIG.data_dir.join(snapshot_path(db_name, name)?);
+    let text = std::fs::read_to_string(&path).map_err(|_| AppError::not_found(format!("Snapshot {name} not found")))?;
+    serde_json::from_str(&text).map_err(AppError::internal)
+}
+
+pub(crate) async fn capture_live_tables(db_name: &str) -> Result<HashMap<String, TableData>, AppError> {
+    with_connection(db_name, |conn| Box::pin(read_live_tables(conn))).await
+}
+
+pub(crate) async fn read_live_tables(conn: &mut DbConn) -> Result<HashMap<String, TableData>, AppError> {
+    let mut tables = HashMap::new();
+    for (table, _) in SNAPSHOT_TABLES {
+        tables.insert(table.to_string(), query_table(conn, &format!("SELECT * FROM [{}]", table), &[]).await?);
+    }
+    Ok(tables)
+}
+
+// Rebuild what a GET endpoint would have returned from the captured tables
+pub(crate) fn snapshot_view(snapshot: &Snapshot, view: SnapshotView) -> TableData {
+    let rules = &snapshot.rules;
+    let empty = TableData::default();
+    let table = |name: &str| snapshot.tables.get(name).unwrap_or(&empty);
+    let (a3, a2, a1, rooms) = (table("Areaslevel3"), table("Areaslevel2"), table("Areaslevel1"), table("Room_Schedule"));
+    let rollup = AreaRollup::new([a3, a2, a1], rooms, rules);
+    let project = |t: &TableData, columns: &[&str], keep: &dyn Fn(&[serde_json::Value]) -> bool| -> TableData {
+        TableData {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: t.rows.iter().filter(|r| keep(r)).map(|r| columns.iter().map(|c| t.get(r, c).cloned().unwrap_or_default()).collect()).collect(),
+            ..Default::default()
+        }
+    };
+    match view {
+        SnapshotView::Areaslevel3 => rollup.area_rows(3, None),
+        SnapshotView::Areaslevel2(parent) => rollup.area_rows(2, Some(parent)),
+        SnapshotView::Areaslevel1(parent) => rollup.area_rows(1, Some(parent)),
+        SnapshotView::RoomSchedule(parent) => project(
+            rooms,
+            &["ParentArea", "Room_Id", "Project_Room_Description", "Ignore_Flag", "Internal_Notes", "Room_Code", "Area"],
+            &|r| parent.is_none_or(|p| same_key(&rooms.cell(r, "ParentArea"), p)),
+        ),
+        SnapshotView::ItemSchedule(room_code) => {
+            let items = table("Item_Schedule");
+            let descriptions = table("Item_descriptions");
+            let mut data = project(
+                items,
+                &["Item_schedule_id", "Item_Ref", "Room_Code", "Ignore_flag", "Qty_New", "Qty_Trans", "Notes"],
+                &|r| same_key(&items.cell(r, "Room_Code"), room_code) && matches!(&*items.cell(r, "instance_variant"), "" | "0"),
+            );
+            // Same shape as the live LEFT JOIN onto Item_descriptions
+            data.columns.insert(2, "Item_Description".into());
+            for row in data.rows.iter_mut() {
+                let description = descriptions.rows.iter()
+                    .find(|d| same_key(&descriptions.cell(d, "ADB_Ref"), &cell_text(&row[1])))
+                    .and_then(|d| descriptions.get(d, "Item_Description").cloned())
+                    .unwrap_or_default();
+                row.insert(2, description);
+            }
+            data
+        }
+    }
+}
+
+pub(crate) async fn snapshot_response(db_name: &str, name: &str, view: SnapshotView<'_>) -> axum::response::Response {
+    let db_name = db_name.to_string();
+    let name = name.to_string();
+    match tokio::task::spawn_blocking(move || load_snapshot(&db_name, &name)).await {
+        Ok(Ok(snapshot)) => Json(snapshot_view(&snapshot, view)).into_response(),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+// Snapshot files may hold numbers as text, so 12.5 and "12.50" count as the same value
+pub(crate) fn same_cell(a: Option<&serde_json::Value>, b: Option<&serde_json::Value>) -> bool {
+    let (a, b) = (a.map(cell_text).unwrap_or_default(), b.map(cell_text).unwrap_or_default());
+    a == b || matches!((a.trim().parse::<f64>(), b.trim().parse::<f64>()), (Ok(x), Ok(y)) if x == y)
+}
+
+pub(crate) fn diff_table(table: &str, key_column: &str, snapshot: &TableData, live: &TableData) -> TableDiff {
+    let key = |t: &TableData, r: &[serde_json::Value]| t.cell(r, key_column).trim_end().to_lowercase();
+    let snap_rows: HashMap<String, &Vec<serde_json::Value>> = snapshot.rows.iter().map(|r| (key(snapshot, r), r)).collect();
+    let live_rows: HashMap<String, &Vec<serde_json::Value>> = live.rows.iter().map(|r| (key(live, r), r)).collect();
+    let mut diff = TableDiff { table: table.to_string(), key_column: key_column.to_string(), added: vec![], removed: vec![], changed: vec![] };
+    for row in &live.rows {
+        match snap_rows.get(&key(live, row)) {
+            None => diff.added.push(row_object(live, row)),
+            Some(old) => {
+                for column in &live.columns {
+                    let (before, after) = (snapshot.get(old, column), live.get(row, column));
+                    if snapshot.col(column).is_some() && !same_cell(before, after) {
+                        diff.changed.push(CellChange {
+                            key: live.cell(row, key_column).to_string(),
+                            column: column.clone(),
+                            snapshot: before.cloned().unwrap_or_default(),
+                            live: after.cloned().unwrap_or_default(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    for row in &snapshot.rows {
+        if !live_rows.contains_key(&key(snapshot, row)) {
+            diff.removed.push(row_object(snapshot, row));
+        }
+    }
+    diff
+}
+
+pub(crate) async fn list_snapshots(Path(db_name): Path<String>) -> axum::response::Response {
+    let dir = CONFIG.data_dir.join("snapshots").join(safe_file_name(&db_name.to_lowercase()));
+    let result = tokio::task::spawn_blocking(move || {
+        let mut snapshots = Vec::new();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => return snapshots,
+        };
+        for entry in entries.flatten() {
+            let Ok(text) = std::fs::read_to_string(entry.path()) else { continue };
+            if let Ok(s) = serde_json::from_str::<Snapshot>(&text) {
+                snapshots.push(SnapshotInfo { name: s.name, note: s.note, created_by: s.created_by, created_at: s.created_at });
+            }
+        }
+        snapshots.sort_by_key(|s| s.created_at);
+        snapshots
+    }).await;
+    match result {
+        Ok(snapshots) => Json(serde_json::json!({ "snapshots": snapshots })).into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+pub(crate) async fn create_snapshot(Path(db_name): Path<String>, Extension(user): Extension<CurrentUser>, Json(payload): Json<CreateSnapshot>) -> axum::response::Response {
+    let CreateSnapshot { name, note } = payload;
+    let name = name.trim().to_string();
+    let path = match snapshot_path(&db_name, &name) {
+        Ok(path) => CONFIG.data_dir.join(path),
+        Err(e) => return e.into_response(),
+    };
+    // Fast path before reading every table; save_new_snapshot makes the real check
+    if matches!(tokio::task::spawn_blocking(move || path.exists()).await, Ok(true)) {
+        return AppError::conflict(format!("Snapshot {name} already exists")).into_response();
+    }
+    let tables = match capture_live_tables(&db_name).await {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let rules = rollup_rules(&db_name).await;
+    let snapshot = Snapshot { name, database: db_name, note, created_by: user.username, created_at: unix_now(), tables, rules };
+    match tokio::task::spawn_blocking(move || save_new_snapshot(&snapshot)).await {
+        Ok(Ok(())) => axum::response::Response::new("OK".into()),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+// Write a snapshot under a name nobody has taken: create_new makes the existence
+// check and the create one step, so two requests for the same name cannot both win
+pub(crate) fn save_new_snapshot(snapshot: &Snapshot) -> Result<(), AppError> {
+    use std::io::Write;
+    let path = CONFIG.data_dir.join(snapshot_path(&snapshot.database, &snapshot.name)?);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(AppError::internal)?;
+    }
+    let text = serde_json::to_string_pretty(snapshot).map_err(AppError::internal)?;
+    let mut file = match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(AppError::conflict(format!("Snapshot {} already exists", snapshot.name)));
+        }
+        Err(e) => return Err(AppError::internal(e)),
+    };
+    if let Err(e) = file.write_all(text.as_bytes()).and_then(|_| file.sync_all()) {
+        let _ = std::fs::remove_file(&path);
+        return Err(AppError::internal(e));
+    }
+    Ok(())
+}
+
+pub(crate) async fn delete_snapshot(Path(db_name): Path<String>, Json(payload): Json<DeleteSnapshot>) -> axum::response::Response {
+    let path = match snapshot_path(&db_name, &payload.name) {
+        Ok(path) => CONFIG.data_dir.join(path),
+        Err(e) => return e.into_response(),
+    };
+    match tokio::task::spawn_blocking(move || std::fs::remove_file(&path)).await {
+        Ok(Ok(())) => axum::response::Response::new("OK".into()),
+        Ok(Err(_)) => AppError::not_found(format!("Snapshot {} not found", payload.name)).into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+pub(crate) async fn diff_snapshot(Path((db_name, name)): Path<(String, String)>) -> axum::response::Response {
+    let snapshot = {
+        let (db_name, name) = (db_name.clone(), name.clone());
+        match tokio::task::spawn_blocking(move || load_snapshot(&db_name, &name)).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => return e.into_response(),
+            Err(e) => return AppError::from(e).into_response(),
+        }
+    };
+    let live = match capture_live_tables(&db_name).await {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    Json(snapshot_diff(&snapshot, &live)).into_response()
+}
+
+// Every snapshot table of `before` against `after`; a missing table reads as empty
+pub(crate) fn diff_tables(before: &HashMap<String, TableData>, after: &HashMap<String, TableData>) -> Vec<TableDiff> {
+    let empty = TableData::default();
+    SNAPSHOT_TABLES.iter()
+        .map(|(table, key)| diff_table(table, key, before.get(*table).unwrap_or(&empty), after.get(*table).unwrap_or(&empty)))
+        .collect()
+}
+
+pub(crate) fn snapshot_diff(snapshot: &Snapshot, live: &HashMap<String, TableData>) -> serde_json::Value {
+    serde_json::json!({ "snapshot": snapshot.name, "created_at": snapshot.created_at, "tables": diff_tables(&snapshot.tables, live) })
+}
+
+// Two project databases compared table by table: `added` rows are only in `other`,
+// `removed` rows only in `database`
+pub(crate) fn database_diff(database: &str, tables: &HashMap<String, TableData>, other: &str, other_tables: &HashMap<String, TableData>) -> serde_json::Value {
+    serde_json::json!({ "database": database, "other": other, "tables": diff_tables(tables, other_tables) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table(columns: &[&str], rows: Vec<Vec<serde_json::Value>>) -> TableData {
+        TableData { columns: columns.iter().map(|c| c.to_string()).collect(), rows, ..Default::default() }
+    }
+
+    #[test]
+    fn diff_table_reports_added_removed_and_changed_rows() {
+        let snapshot = table(&["Room_Id", "Area"], vec![
+            vec![json!("R1"), json!("12.50")],
+            vec![json!("R2"), json!(8)],
+            vec![json!("R4"), json!(3)],
+        ]);
+        let live = table(&["Room_Id", "Area"], vec![
+            vec![json!("R1"), json!(12.5)],
+            vec![json!("R2"), json!(9)],
+            vec![json!("R3"), json!(1)],
+        ]);
+        let diff = diff_table("Room_Schedule", "Room_Id", &snapshot, &live);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0]["Room_Id"], json!("R3"));
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0]["Room_Id"], json!("R4"));
+        // 12.50 and 12.5 are the same value; only R2's area changed
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!((diff.changed[0].key.as_str(), diff.changed[0].column.as_str()), ("R2", "Area"));
+        assert_eq!((&diff.changed[0].snapshot, &diff.changed[0].live), (&json!(8), &json!(9)));
+    }
+
+    #[test]
+    fn areaslevel3_rows_are_matched_by_parent_area() {
+        let before = HashMap::from([("Areaslevel3".to_string(), table(&["ParentArea", "Uplift"], vec![vec![json!("Block A"), json!(5)]]))]);
+        let after = HashMap::from([("Areaslevel3".to_string(), table(&["ParentArea", "Uplift"], vec![vec![json!("Block A"), json!(7)]]))]);
+        let diffs = diff_tables(&before, &after);
+        let a3 = diffs.iter().find(|d| d.table == "Areaslevel3").unwrap();
+        assert!(a3.added.is_empty() && a3.removed.is_empty());
+        assert_eq!((a3.changed[0].key.as_str(), a3.changed[0].column.as_str()), ("Block A", "Uplift"));
+    }
+
+    #[test]
+    fn snapshot_names_must_be_their_own_file_name() {
+        assert!(snapshot_path("Main", "Stage 2 issue_v1-final").is_ok());
+        for name in ["", "a/b", "../issue", "Stage 2.1", "café"] {
+            assert!(check_snapshot_name(name).is_err(), "{name:?} was accepted");
+        }
+    }
+}